@@ -1,13 +1,38 @@
 #!/usr/bin/env python3
 from http.server import HTTPServer, SimpleHTTPRequestHandler, test
+import os
 import sys
 
 class CORSRequestHandler(SimpleHTTPRequestHandler):
+    # Keep the connection open between uploads
+    protocol_version = 'HTTP/1.1'
+
     def end_headers(self):
         self.send_header('Access-Control-Allow-Origin', '*')
         self.send_header('Access-Control-Allow-Methods', 'GET')
         self.send_header('Access-Control-Allow-Headers', '*')
         SimpleHTTPRequestHandler.end_headers(self)
 
+    def do_PUT(self):
+        path = self.translate_path(self.path)
+        os.makedirs(os.path.dirname(path), exist_ok=True)
+        length = int(self.headers.get('Content-Length', 0))
+        with open(path + '.tmp', 'wb') as f:
+            f.write(self.rfile.read(length))
+        os.replace(path + '.tmp', path)
+        self.send_response(201)
+        self.send_header('Content-Length', '0')
+        self.end_headers()
+
+    def do_DELETE(self):
+        path = self.translate_path(self.path)
+        if os.path.isfile(path):
+            os.remove(path)
+            self.send_response(204)
+        else:
+            self.send_response(404)
+        self.send_header('Content-Length', '0')
+        self.end_headers()
+
 if __name__ == '__main__':
     test(CORSRequestHandler, HTTPServer, port=int(sys.argv[1]) if len(sys.argv) > 1 else 8000)
@@ -0,0 +1,123 @@
+/// Returns the RFC 6381 codec string of a stream, as used by the `CODECS` playlist attribute.
+pub fn codec_string(s: &gst::StructureRef) -> Option<String> {
+    match s.name() {
+        "video/x-h264" => h264_codec_string(s),
+        "video/x-h265" => h265_codec_string(s),
+        "audio/mpeg" => match s.get::<i32>("mpegversion").ok()? {
+            1 => Some("mp4a.40.34".to_string()),
+            2 | 4 => match s.get::<String>("profile").ok().as_deref() {
+                Some("he-aac") => Some("mp4a.40.5".to_string()),
+                _ => Some("mp4a.40.2".to_string()),
+            },
+            _ => None,
+        },
+        "audio/x-ac3" => Some("ac-3".to_string()),
+        "audio/x-eac3" => Some("ec-3".to_string()),
+        _ => None,
+    }
+}
+
+fn h264_codec_string(s: &gst::StructureRef) -> Option<String> {
+    // The `avcC` codec data starts with the profile, constraint flags and level bytes.
+    if let Ok(codec_data) = s.get::<gst::Buffer>("codec_data") {
+        let map = codec_data.map_readable().ok()?;
+        if map.len() >= 4 {
+            return Some(format!("avc1.{:02X}{:02X}{:02X}", map[1], map[2], map[3]));
+        }
+    }
+
+    let (profile_idc, constraint_flags) = match s.get::<String>("profile").ok()?.as_str() {
+        "constrained-baseline" => (66, 0xc0),
+        "baseline" => (66, 0x80),
+        "main" => (77, 0x40),
+        "extended" => (88, 0x00),
+        "high" => (100, 0x00),
+        "high-10" => (110, 0x00),
+        "high-4:2:2" => (122, 0x00),
+        "high-4:4:4" => (244, 0x00),
+        _ => return None,
+    };
+    let level_idc = parse_level(&s.get::<String>("level").ok()?, 10)?;
+
+    Some(format!(
+        "avc1.{:02X}{:02X}{:02X}",
+        profile_idc, constraint_flags, level_idc
+    ))
+}
+
+fn h265_codec_string(s: &gst::StructureRef) -> Option<String> {
+    let (profile_idc, compatibility_flags) = match s.get::<String>("profile").ok()?.as_str() {
+        "main" => (1, 6),
+        "main-10" => (2, 4),
+        "main-still-picture" => (3, 8),
+        _ => return None,
+    };
+    let tier = match s.get::<String>("tier").ok().as_deref() {
+        Some("high") => 'H',
+        _ => 'L',
+    };
+    let level_idc = parse_level(&s.get::<String>("level").ok()?, 30)?;
+
+    Some(format!(
+        "hvc1.{}.{}.{}{}.B0",
+        profile_idc, compatibility_flags, tier, level_idc
+    ))
+}
+
+/// Parses a `major.minor` level as signalled in caps, e.g. `3.1` with `scale` 10 gives 31.
+fn parse_level(level: &str, scale: u32) -> Option<u32> {
+    let mut numbers = level.splitn(2, '.');
+    let major = numbers.next()?.parse::<u32>().ok()?;
+    let minor = match numbers.next() {
+        Some(minor) => minor.parse::<u32>().ok()?,
+        None => 0,
+    };
+    Some(major * scale + minor * scale / 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn h264_codec_string_from_profile_and_level() {
+        gst::init().unwrap();
+
+        let caps = gst::Caps::builder("video/x-h264")
+            .field("profile", &"high")
+            .field("level", &"3.1")
+            .build();
+        assert_eq!(
+            codec_string(caps.structure(0).unwrap()),
+            Some("avc1.64001F".to_string())
+        );
+    }
+
+    #[test]
+    fn h265_codec_string_from_profile_and_level() {
+        gst::init().unwrap();
+
+        let caps = gst::Caps::builder("video/x-h265")
+            .field("profile", &"main")
+            .field("tier", &"main")
+            .field("level", &"3.1")
+            .build();
+        assert_eq!(
+            codec_string(caps.structure(0).unwrap()),
+            Some("hvc1.1.6.L93.B0".to_string())
+        );
+    }
+
+    #[test]
+    fn aac_codec_string() {
+        gst::init().unwrap();
+
+        let caps = gst::Caps::builder("audio/mpeg")
+            .field("mpegversion", &4i32)
+            .build();
+        assert_eq!(
+            codec_string(caps.structure(0).unwrap()),
+            Some("mp4a.40.2".to_string())
+        );
+    }
+}
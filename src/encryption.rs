@@ -0,0 +1,116 @@
+use aes::Aes128;
+use block_modes::block_padding::Pkcs7;
+use block_modes::{BlockMode, Cbc};
+use glib::subclass::prelude::*;
+
+pub const KEY_LENGTH: usize = 16;
+
+type Aes128Cbc = Cbc<Aes128, Pkcs7>;
+
+glib::wrapper! {
+    /// Output stream encrypting everything written to it with AES-128-CBC and PKCS7 padding
+    /// before passing it on to the base stream, as expected by `EXT-X-KEY:METHOD=AES-128`.
+    pub struct AesOutputStream(ObjectSubclass<imp::AesOutputStream>) @extends gio::OutputStream;
+}
+
+unsafe impl Send for AesOutputStream {}
+unsafe impl Sync for AesOutputStream {}
+
+impl AesOutputStream {
+    pub fn new(base_stream: gio::OutputStream, key: &[u8; KEY_LENGTH], iv: &[u8; 16]) -> Self {
+        let stream = glib::Object::new::<Self>(&[]).expect("Failed to create AES output stream");
+
+        let imp = imp::AesOutputStream::from_instance(&stream);
+        *imp.encryptor.lock().unwrap() = Some(imp::Encryptor {
+            base_stream,
+            cipher: Aes128Cbc::new_from_slices(key, iv).expect("valid key and IV lengths"),
+            pending: vec![],
+        });
+
+        stream
+    }
+}
+
+mod imp {
+    use super::*;
+    use gio::prelude::*;
+    use gio::subclass::prelude::*;
+    use std::sync::Mutex;
+
+    const BLOCK_SIZE: usize = 16;
+
+    pub struct Encryptor {
+        pub base_stream: gio::OutputStream,
+        pub cipher: Aes128Cbc,
+        /// Bytes not encrypted yet because they do not fill a whole block.
+        pub pending: Vec<u8>,
+    }
+
+    #[derive(Default)]
+    pub struct AesOutputStream {
+        pub encryptor: Mutex<Option<Encryptor>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for AesOutputStream {
+        const NAME: &'static str = "FlexHlsSinkAesOutputStream";
+        type Type = super::AesOutputStream;
+        type ParentType = gio::OutputStream;
+    }
+
+    impl ObjectImpl for AesOutputStream {}
+
+    fn closed_error() -> glib::Error {
+        glib::Error::new(gio::IOErrorEnum::Closed, "Stream is already closed")
+    }
+
+    impl OutputStreamImpl for AesOutputStream {
+        fn write(
+            &self,
+            _stream: &Self::Type,
+            buffer: &[u8],
+            cancellable: Option<&gio::Cancellable>,
+        ) -> Result<usize, glib::Error> {
+            let mut encryptor = self.encryptor.lock().unwrap();
+            let encryptor = encryptor.as_mut().ok_or_else(closed_error)?;
+
+            encryptor.pending.extend_from_slice(buffer);
+            let complete_len = encryptor.pending.len() - encryptor.pending.len() % BLOCK_SIZE;
+            let mut data = encryptor.pending.drain(..complete_len).collect::<Vec<u8>>();
+            for block in data.chunks_exact_mut(BLOCK_SIZE) {
+                encryptor
+                    .cipher
+                    .encrypt_blocks(std::slice::from_mut(aes::Block::from_mut_slice(block)));
+            }
+            encryptor.base_stream.write_all(&data, cancellable)?;
+
+            Ok(buffer.len())
+        }
+
+        fn flush(
+            &self,
+            _stream: &Self::Type,
+            cancellable: Option<&gio::Cancellable>,
+        ) -> Result<(), glib::Error> {
+            let encryptor = self.encryptor.lock().unwrap();
+            let encryptor = encryptor.as_ref().ok_or_else(closed_error)?;
+            encryptor.base_stream.flush(cancellable)
+        }
+
+        fn close(
+            &self,
+            _stream: &Self::Type,
+            cancellable: Option<&gio::Cancellable>,
+        ) -> Result<(), glib::Error> {
+            let encryptor = match self.encryptor.lock().unwrap().take() {
+                Some(encryptor) => encryptor,
+                None => return Ok(()),
+            };
+
+            // Padding is only added to the last block, so the stream must be closed to be valid.
+            let last_blocks = encryptor.cipher.encrypt_vec(&encryptor.pending);
+            encryptor.base_stream.write_all(&last_blocks, cancellable)?;
+            encryptor.base_stream.close(cancellable)
+        }
+    }
+}
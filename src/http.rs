@@ -0,0 +1,366 @@
+use crate::storage::HlsStorage;
+use glib::Cast;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Delay before retrying a failed request, doubled after each attempt.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Longest delay asked by a server with `Retry-After` that is honoured.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest wait for room in the upload queue when a file is closed, the upload then fails.
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Request sent by the upload thread.
+enum Upload {
+    Put { location: String, content: Vec<u8> },
+    Delete { location: String },
+}
+
+impl Upload {
+    fn location(&self) -> &str {
+        match self {
+            Upload::Put { location, .. } | Upload::Delete { location } => location,
+        }
+    }
+
+    fn send(&self, agent: &ureq::Agent, base_url: &str) -> Result<(), ureq::Error> {
+        let url = url_for(base_url, self.location());
+        let response = match self {
+            Upload::Put { content, .. } => agent.put(&url).send_bytes(content)?,
+            Upload::Delete { .. } => agent.delete(&url).call()?,
+        };
+        // The connection only goes back to the pool once the response is read.
+        let _ = io::copy(&mut response.into_reader(), &mut io::sink());
+        Ok(())
+    }
+}
+
+/// URL of the file at `location` under `base_url`.
+fn url_for(base_url: &str, location: &str) -> String {
+    format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        location.trim_start_matches("./").trim_start_matches('/')
+    )
+}
+
+/// Delay asked by a `Retry-After` header, when given in seconds.
+fn retry_after(value: &str) -> Option<Duration> {
+    value
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(|secs| Duration::from_secs(secs).min(MAX_RETRY_AFTER))
+}
+
+/// Sends the queued uploads in order, retrying the failed ones with a growing delay, or after
+/// the delay asked by the server.
+///
+/// Requests rejected by the server (4xx) are not retried, except on timeouts (408) and rate
+/// limiting (429).
+fn upload_loop(
+    agent: ureq::Agent,
+    base_url: String,
+    retries: u32,
+    receiver: mpsc::Receiver<Upload>,
+    failure: Arc<Mutex<Option<String>>>,
+) {
+    for upload in receiver {
+        let mut attempt = 0;
+        let mut delay = INITIAL_RETRY_DELAY;
+        loop {
+            let err = match upload.send(&agent, &base_url) {
+                Ok(()) => break,
+                // Already gone, e.g. deleted before a restart.
+                Err(ureq::Error::Status(404, _)) if matches!(upload, Upload::Delete { .. }) => {
+                    break
+                }
+                Err(err) => err,
+            };
+            let (rejected, server_delay) = match &err {
+                ureq::Error::Status(status, response) => (
+                    *status < 500 && *status != 408 && *status != 429,
+                    response.header("Retry-After").and_then(retry_after),
+                ),
+                _ => (false, None),
+            };
+            if rejected || attempt >= retries {
+                *failure.lock().unwrap() =
+                    Some(format!("Could not upload {}: {}", upload.location(), err));
+                break;
+            }
+
+            attempt += 1;
+            thread::sleep(server_delay.unwrap_or(delay));
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+/// Where the content written to an `HttpStorage` stream goes once the stream is closed.
+enum Destination {
+    /// Segments are uploaded, a failure to queue them is returned by the next attempt to write
+    /// a file.
+    Queue(mpsc::SyncSender<Upload>, Arc<Mutex<Option<String>>>),
+    /// Playlists wait for `commit_playlist`.
+    PendingPlaylists(Arc<Mutex<HashMap<String, Vec<u8>>>>),
+}
+
+/// Collects the content of a file, sent once the stream is closed and the writer dropped.
+struct HttpWriter {
+    location: String,
+    content: Vec<u8>,
+    destination: Destination,
+}
+
+impl Write for HttpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.content.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Queues `upload`, waiting for room in the queue for up to `QUEUE_TIMEOUT`.
+fn queue_with_timeout(sender: &mpsc::SyncSender<Upload>, mut upload: Upload) -> io::Result<()> {
+    let deadline = Instant::now() + QUEUE_TIMEOUT;
+    loop {
+        upload = match sender.try_send(upload) {
+            Ok(()) => return Ok(()),
+            Err(mpsc::TrySendError::Full(upload)) if Instant::now() < deadline => upload,
+            Err(mpsc::TrySendError::Full(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "The upload queue stayed full",
+                ))
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => return Err(upload_thread_stopped()),
+        };
+        thread::sleep(QUEUE_POLL_INTERVAL);
+    }
+}
+
+impl Drop for HttpWriter {
+    /// Queues the upload once the stream is closed, which takes the writer out of it.
+    fn drop(&mut self) {
+        let location = std::mem::take(&mut self.location);
+        let content = std::mem::take(&mut self.content);
+        match &self.destination {
+            Destination::Queue(sender, failure) => {
+                let upload = Upload::Put {
+                    location: location.clone(),
+                    content,
+                };
+                if let Err(err) = queue_with_timeout(sender, upload) {
+                    *failure.lock().unwrap() =
+                        Some(format!("Could not upload {}: {}", location, err));
+                }
+            }
+            Destination::PendingPlaylists(playlists) => {
+                playlists.lock().unwrap().insert(location, content);
+            }
+        }
+    }
+}
+
+fn upload_thread_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "The upload thread is stopped")
+}
+
+fn to_io_error(err: ureq::Error) -> io::Error {
+    match err {
+        ureq::Error::Status(404, _) => io::Error::new(io::ErrorKind::NotFound, err.to_string()),
+        err => io::Error::new(io::ErrorKind::Other, err.to_string()),
+    }
+}
+
+/// Storage uploading the files to an HTTP server, e.g. an origin ingesting HLS, under a base
+/// URL the locations are appended to.
+///
+/// Segments and playlists are sent with `PUT` requests and deleted with `DELETE` requests, in
+/// order, from a thread reusing its connections. At most `queue_size` uploads wait for that
+/// thread, closing a segment while the queue is full waits for one to complete, for a bounded
+/// time. Failed requests are retried `retries` times, the last failure is then returned by the
+/// next attempt to write a file.
+pub struct HttpStorage {
+    base_url: String,
+    agent: ureq::Agent,
+    sender: Mutex<Option<mpsc::SyncSender<Upload>>>,
+    upload_thread: Mutex<Option<thread::JoinHandle<()>>>,
+    pending_playlists: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl HttpStorage {
+    pub fn new(base_url: &str, retries: u32, queue_size: usize) -> io::Result<Self> {
+        let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let failure = Arc::new(Mutex::new(None));
+
+        let upload_thread = {
+            let agent = agent.clone();
+            let base_url = base_url.to_string();
+            let failure = failure.clone();
+            thread::Builder::new()
+                .name("flexhlssink-upload".into())
+                .spawn(move || upload_loop(agent, base_url, retries, receiver, failure))?
+        };
+
+        Ok(Self {
+            base_url: base_url.to_string(),
+            agent,
+            sender: Mutex::new(Some(sender)),
+            upload_thread: Mutex::new(Some(upload_thread)),
+            pending_playlists: Arc::new(Mutex::new(HashMap::new())),
+            failure,
+        })
+    }
+
+    /// Returns the last failed upload, if any since the previous call.
+    fn check_failure(&self) -> io::Result<()> {
+        match self.failure.lock().unwrap().take() {
+            Some(failure) => Err(io::Error::new(io::ErrorKind::Other, failure)),
+            None => Ok(()),
+        }
+    }
+
+    fn sender(&self) -> io::Result<mpsc::SyncSender<Upload>> {
+        self.sender
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(upload_thread_stopped)
+    }
+
+    fn queue(&self, upload: Upload) -> io::Result<()> {
+        self.sender()?
+            .send(upload)
+            .map_err(|_| upload_thread_stopped())
+    }
+
+    fn stream(&self, location: &str, destination: Destination) -> gio::OutputStream {
+        let writer = HttpWriter {
+            location: location.to_string(),
+            content: vec![],
+            destination,
+        };
+        gio::WriteOutputStream::new(writer).upcast()
+    }
+}
+
+impl HlsStorage for HttpStorage {
+    fn open_segment(&self, location: &str) -> io::Result<gio::OutputStream> {
+        self.check_failure()?;
+        Ok(self.stream(
+            location,
+            Destination::Queue(self.sender()?, self.failure.clone()),
+        ))
+    }
+
+    fn open_playlist(&self, location: &str) -> io::Result<gio::OutputStream> {
+        self.check_failure()?;
+        Ok(self.stream(
+            location,
+            Destination::PendingPlaylists(self.pending_playlists.clone()),
+        ))
+    }
+
+    fn commit_playlist(&self, location: &str) -> io::Result<()> {
+        self.check_failure()?;
+        let content = self
+            .pending_playlists
+            .lock()
+            .unwrap()
+            .remove(location)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No playlist written to {}", location),
+                )
+            })?;
+        self.queue(Upload::Put {
+            location: location.to_string(),
+            content,
+        })
+    }
+
+    fn delete(&self, location: &str) -> io::Result<()> {
+        self.queue(Upload::Delete {
+            location: location.to_string(),
+        })
+    }
+
+    fn list(&self, _prefix: &str) -> io::Result<Vec<String>> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Files can not be listed over HTTP",
+        ))
+    }
+
+    fn read(&self, location: &str) -> io::Result<Vec<u8>> {
+        let response = self
+            .agent
+            .get(&url_for(&self.base_url, location))
+            .call()
+            .map_err(to_io_error)?;
+        let mut content = vec![];
+        response.into_reader().read_to_end(&mut content)?;
+        Ok(content)
+    }
+}
+
+impl Drop for HttpStorage {
+    /// Waits for the queued uploads to complete.
+    fn drop(&mut self) {
+        self.sender.lock().unwrap().take();
+        if let Some(upload_thread) = self.upload_thread.lock().unwrap().take() {
+            let _ = upload_thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations_appended_to_base_url() {
+        assert_eq!(
+            url_for("http://localhost:8000/live/", "segment00001.ts"),
+            "http://localhost:8000/live/segment00001.ts"
+        );
+        assert_eq!(
+            url_for("http://localhost:8000", "./hls/playlist.m3u8"),
+            "http://localhost:8000/hls/playlist.m3u8"
+        );
+    }
+
+    #[test]
+    fn retry_after_seconds_capped() {
+        assert_eq!(retry_after(" 2"), Some(Duration::from_secs(2)));
+        assert_eq!(retry_after("3600"), Some(MAX_RETRY_AFTER));
+        assert_eq!(retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    #[test]
+    fn queue_fails_once_upload_thread_stopped() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let upload = || Upload::Delete {
+            location: "segment00001.ts".to_string(),
+        };
+        assert!(queue_with_timeout(&sender, upload()).is_ok());
+
+        drop(receiver);
+        let err = queue_with_timeout(&sender, upload()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
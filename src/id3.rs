@@ -0,0 +1,98 @@
+/// Owner of the PRIV frame holding the timestamp of the first sample of a packed audio segment.
+const TRANSPORT_STREAM_TIMESTAMP_OWNER: &str = "com.apple.streaming.transportStreamTimestamp";
+
+/// MPEG-2 timestamps are 33-bit wide.
+const MPEG_TIMESTAMP_MASK: u64 = 0x1_ffff_ffff;
+
+/// Encodes a size as the 4 bytes syncsafe integer used by ID3v2.4.
+fn syncsafe(size: usize) -> [u8; 4] {
+    [
+        (size >> 21) as u8 & 0x7f,
+        (size >> 14) as u8 & 0x7f,
+        (size >> 7) as u8 & 0x7f,
+        size as u8 & 0x7f,
+    ]
+}
+
+/// Makes an ID3v2.4 frame with the given identifier, e.g. `PRIV`.
+pub fn frame(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(10 + data.len());
+    frame.extend_from_slice(id);
+    frame.extend_from_slice(&syncsafe(data.len()));
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Makes an ID3v2.4 tag holding the given frames.
+pub fn tag(frames: &[Vec<u8>]) -> Vec<u8> {
+    let size = frames.iter().map(|frame| frame.len()).sum::<usize>();
+    let mut tag = Vec::with_capacity(10 + size);
+    tag.extend_from_slice(b"ID3");
+    tag.extend_from_slice(&[4, 0, 0]);
+    tag.extend_from_slice(&syncsafe(size));
+    for frame in frames {
+        tag.extend_from_slice(frame);
+    }
+    tag
+}
+
+/// Makes an ID3v2.4 text information frame, e.g. `TIT2` for the title.
+fn text_frame(id: &[u8; 4], text: &str) -> Vec<u8> {
+    // UTF-8 encoding
+    let mut data = vec![3];
+    data.extend_from_slice(text.as_bytes());
+    frame(id, &data)
+}
+
+/// Makes an ID3 tag with the title, artist, album, genre and comment of a tag list, `None` when
+/// it has none of them.
+pub fn tag_from_tags(tags: &gst::TagListRef) -> Option<Vec<u8>> {
+    let mut frames = vec![];
+    if let Some(title) = tags.get::<gst::tags::Title>() {
+        frames.push(text_frame(b"TIT2", title.get()));
+    }
+    if let Some(artist) = tags.get::<gst::tags::Artist>() {
+        frames.push(text_frame(b"TPE1", artist.get()));
+    }
+    if let Some(album) = tags.get::<gst::tags::Album>() {
+        frames.push(text_frame(b"TALB", album.get()));
+    }
+    if let Some(genre) = tags.get::<gst::tags::Genre>() {
+        frames.push(text_frame(b"TCON", genre.get()));
+    }
+    if let Some(comment) = tags.get::<gst::tags::Comment>() {
+        // UTF-8 encoding, undetermined language and empty description
+        let mut data = b"\x03und\x00".to_vec();
+        data.extend_from_slice(comment.get().as_bytes());
+        frames.push(frame(b"COMM", &data));
+    }
+
+    if frames.is_empty() {
+        return None;
+    }
+    Some(tag(&frames))
+}
+
+/// Makes the ID3 tag required at the start of each packed audio segment, holding the MPEG-2
+/// timestamp (90kHz) of its first sample.
+pub fn transport_stream_timestamp_tag(timestamp: u64) -> Vec<u8> {
+    let mut data = TRANSPORT_STREAM_TIMESTAMP_OWNER.as_bytes().to_vec();
+    data.push(0);
+    data.extend_from_slice(&(timestamp & MPEG_TIMESTAMP_MASK).to_be_bytes());
+    tag(&[frame(b"PRIV", &data)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport_stream_timestamp() {
+        let tag = transport_stream_timestamp_tag(0x1_2345_6789);
+        assert_eq!(tag.len(), 73);
+        assert_eq!(&tag[..10], b"ID3\x04\x00\x00\x00\x00\x00\x3f");
+        assert_eq!(&tag[10..20], b"PRIV\x00\x00\x00\x35\x00\x00");
+        assert_eq!(&tag[65..], &[0, 0, 0, 1, 0x23, 0x45, 0x67, 0x89]);
+    }
+}
@@ -1,29 +1,82 @@
-use crate::playlist::PlaylistRenderState;
+use crate::encryption::{AesOutputStream, KEY_LENGTH};
+use crate::http::HttpStorage;
+use crate::id3;
+use crate::playlist::{
+    ByteRange, CueTag, IFrame, MediaPlaylist, PartialSegment, PlaylistRenderState, PreloadHint,
+};
+use crate::storage::{FsStorage, HlsStorage};
+use crate::webvtt::{self, Cue};
+use crate::writer::WriteQueue;
+use chrono::{SecondsFormat, TimeZone, Utc};
 use gio::prelude::*;
 use glib::subclass::prelude::*;
 use gst::prelude::*;
 use gst::subclass::prelude::*;
 use gst::{gst_debug, gst_error, gst_info, gst_trace, gst_warning};
-use m3u8_rs::playlist::{MediaPlaylist, MediaSegment};
+use m3u8_rs::playlist::{Key, Map, MediaPlaylistType, MediaSegment};
 use once_cell::sync::Lazy;
-use std::fs;
+use std::collections::HashMap;
 use std::io::Write;
 use std::path;
 use std::sync::{Arc, Mutex};
 
 const DEFAULT_LOCATION: &str = "segment%05d.ts";
+const DEFAULT_INIT_LOCATION: &str = "init.mp4";
+const DEFAULT_KEY_LOCATION: &str = "key%05d.key";
 const DEFAULT_PLAYLIST_LOCATION: &str = "playlist.m3u8";
+const DEFAULT_SUBTITLE_LOCATION: &str = "subtitle%u_segment%05d.vtt";
+const DEFAULT_SUBTITLE_PLAYLIST_LOCATION: &str = "subtitle%u.m3u8";
 const DEFAULT_MAX_NUM_SEGMENT_FILES: u32 = 10;
 const DEFAULT_TARGET_DURATION: u32 = 15;
 const DEFAULT_PLAYLIST_LENGTH: u32 = 5;
 const DEFAULT_SEND_KEYFRAME_REQUESTS: bool = true;
+const DEFAULT_SEGMENT_FORMAT: SegmentFormat = SegmentFormat::Ts;
+const DEFAULT_PART_DURATION: u32 = 0;
+const DEFAULT_ENCRYPTION: Encryption = Encryption::None;
+const DEFAULT_KEY_ROTATION: u32 = 0;
+const DEFAULT_PROGRAM_DATE_TIME: ProgramDateTime = ProgramDateTime::None;
+const DEFAULT_PLAYLIST_TYPE: PlaylistType = PlaylistType::None;
+const DEFAULT_SINGLE_FILE: bool = false;
+const DEFAULT_CUE_TAGS: bool = false;
+const DEFAULT_ALIGN_TO_EPOCH: bool = false;
+const DEFAULT_RESUME: bool = false;
+const DEFAULT_UPLOAD_RETRIES: u32 = 3;
+const DEFAULT_UPLOAD_QUEUE_SIZE: u32 = 16;
+const DEFAULT_WRITE_QUEUE_SIZE: u32 = 32;
+const DEFAULT_WRITE_QUEUE_FULL: WriteQueueFull = WriteQueueFull::Block;
+const DEFAULT_TARGET_DURATION_POLICY: TargetDurationPolicy = TargetDurationPolicy::Strict;
 
 const GST_M3U8_PLAYLIST_VERSION: usize = 3;
+const GST_M3U8_PLAYLIST_BYTERANGE_VERSION: usize = 4;
+const GST_M3U8_PLAYLIST_FMP4_VERSION: usize = 6;
 const BACKWARDS_COMPATIBLE_PLACEHOLDER: &str = "%05d";
+const PAD_INDEX_PLACEHOLDER: &str = "%u";
+/// Duration of the subtitle cues without a duration of their own.
+const DEFAULT_CUE_DURATION: u64 = 2 * gst::SECOND_VAL;
+/// `mpegtsmux` offsets the running time by one hour in the timestamps (90kHz) of the stream.
+const MPEGTS_CLOCK_BASE: u64 = 90_000 * 3_600;
+/// Seconds between the NTP epoch (1900) and the UNIX epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+/// Longest time the metadata stream lags behind the media streams when no tags are inserted.
+const META_GAP_INTERVAL: u64 = 100 * gst::MSECOND_VAL;
 
 const SIGNAL_GET_PLAYLIST_STREAM: &str = "get-playlist-stream";
 const SIGNAL_GET_FRAGMENT_STREAM: &str = "get-fragment-stream";
 const SIGNAL_DELETE_FRAGMENT: &str = "delete-fragment";
+const SIGNAL_COMMIT_PLAYLIST_STREAM: &str = "commit-playlist-stream";
+const SIGNAL_GET_KEY: &str = "get-key";
+const SIGNAL_KEY_ROTATED: &str = "key-rotated";
+const SIGNAL_INSERT_METADATA: &str = "insert-metadata";
+const SIGNAL_SPLIT_NOW: &str = "split-now";
+
+/// Name of the custom events requesting a new segment at the next keyframe, sent downstream
+/// through a sink pad or to the element.
+const SPLIT_NOW_EVENT: &str = "split-now";
+
+/// Name of the custom downstream events describing an SCTE-35 splice point, with the fields
+/// `event-id` (u32), `out-of-network` (bool), and optionally `running-time` and `duration`
+/// (ClockTime) and `splice-info` (the binary `splice_info_section` as GBytes).
+const SCTE35_SPLICE_EVENT: &str = "scte35-splice";
 
 static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     gst::DebugCategory::new(
@@ -33,36 +86,398 @@ static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     )
 });
 
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::GEnum)]
+#[repr(u32)]
+#[genum(type_name = "GstFlexHlsSinkSegmentFormat")]
+pub enum SegmentFormat {
+    #[genum(name = "MPEG-TS segments", nick = "ts")]
+    Ts = 0,
+    #[genum(name = "Fragmented MP4 (CMAF) segments", nick = "fmp4")]
+    Fmp4 = 1,
+    #[genum(
+        name = "Packed audio (ADTS AAC or MP3) segments",
+        nick = "packed-audio"
+    )]
+    PackedAudio = 2,
+}
+
+impl SegmentFormat {
+    fn playlist_version(&self) -> usize {
+        match self {
+            SegmentFormat::Ts | SegmentFormat::PackedAudio => GST_M3U8_PLAYLIST_VERSION,
+            SegmentFormat::Fmp4 => GST_M3U8_PLAYLIST_FMP4_VERSION,
+        }
+    }
+
+    fn make_muxer(&self, target_duration: u32) -> gst::Element {
+        match self {
+            SegmentFormat::Ts => gst::ElementFactory::make("mpegtsmux", Some("mpeg-ts_mux"))
+                .expect("Could not make element mpegtsmux"),
+            SegmentFormat::Fmp4 => {
+                let mux = gst::ElementFactory::make("mp4mux", Some("fmp4_mux"))
+                    .expect("Could not make element mp4mux");
+                mux.set_properties(&[
+                    ("fragment-duration", &(target_duration * 1_000)),
+                    ("streamable", &true),
+                ])
+                .unwrap();
+                mux
+            }
+            // Packed audio is the elementary stream itself, splitmuxsink links it to the
+            // static sink pad of a passthrough in place of a muxer.
+            SegmentFormat::PackedAudio => {
+                gst::ElementFactory::make("identity", Some("packed-audio_mux"))
+                    .expect("Could not make element identity")
+            }
+        }
+    }
+
+    /// Whether the muxer must be reset at each fragment. The fragmented MP4 muxer writes a fresh
+    /// `ftyp`/`moov` header for each fragment, which is split out into the init segment.
+    fn reset_muxer(&self) -> bool {
+        matches!(self, SegmentFormat::Fmp4)
+    }
+
+    /// MPEG-TS timestamp of the running time 0 in the segments, for `X-TIMESTAMP-MAP`.
+    fn mpegts_timestamp_offset(&self) -> u64 {
+        match self {
+            SegmentFormat::Ts | SegmentFormat::PackedAudio => MPEGTS_CLOCK_BASE,
+            SegmentFormat::Fmp4 => 0,
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::GEnum)]
+#[repr(u32)]
+#[genum(type_name = "GstFlexHlsSinkEncryption")]
+pub enum Encryption {
+    #[genum(name = "No encryption", nick = "none")]
+    None = 0,
+    #[genum(name = "AES-128-CBC encryption of whole segments", nick = "aes-128")]
+    Aes128 = 1,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::GEnum)]
+#[repr(u32)]
+#[genum(type_name = "GstFlexHlsSinkProgramDateTime")]
+pub enum ProgramDateTime {
+    #[genum(name = "No program date time", nick = "none")]
+    None = 0,
+    #[genum(name = "Wall-clock time of the pipeline clock", nick = "clock")]
+    Clock = 1,
+    #[genum(
+        name = "Reference timestamp meta of the buffers, falling back to the pipeline clock",
+        nick = "reference-timestamp"
+    )]
+    ReferenceTimestamp = 2,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::GEnum)]
+#[repr(u32)]
+#[genum(type_name = "GstFlexHlsSinkPlaylistType")]
+pub enum PlaylistType {
+    #[genum(name = "Live playlist sliding over the last segments", nick = "none")]
+    None = 0,
+    #[genum(name = "Event playlist only appending segments", nick = "event")]
+    Event = 1,
+    #[genum(name = "Video on demand playlist written once complete", nick = "vod")]
+    Vod = 2,
+}
+
+impl PlaylistType {
+    fn media_playlist_type(&self) -> Option<MediaPlaylistType> {
+        match self {
+            PlaylistType::None => None,
+            PlaylistType::Event => Some(MediaPlaylistType::Event),
+            PlaylistType::Vod => Some(MediaPlaylistType::Vod),
+        }
+    }
+
+    /// Whether segments slide out of the playlist, their files being deleted.
+    fn is_sliding(&self) -> bool {
+        matches!(self, PlaylistType::None)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::GEnum)]
+#[repr(u32)]
+#[genum(type_name = "GstFlexHlsSinkWriteQueueFull")]
+pub enum WriteQueueFull {
+    #[genum(name = "Wait for room in the write queue", nick = "block")]
+    Block = 0,
+    #[genum(
+        name = "Drop the rest of the segment and mark it with EXT-X-GAP",
+        nick = "drop-segment"
+    )]
+    DropSegment = 1,
+    #[genum(name = "Post an error", nick = "error")]
+    Error = 2,
+}
+
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy, glib::GEnum)]
+#[repr(u32)]
+#[genum(type_name = "GstFlexHlsSinkTargetDurationPolicy")]
+pub enum TargetDurationPolicy {
+    #[genum(
+        name = "Leave the segments longer than the target duration out of the playlist",
+        nick = "strict"
+    )]
+    Strict = 0,
+    #[genum(
+        name = "Raise the target duration until the first playlist is written, then leave the longer segments out",
+        nick = "bump"
+    )]
+    Bump = 1,
+}
+
+/// Storage emitting the stream signals, so their handlers run on the writer thread.
+struct SignalStorage(glib::WeakRef<super::FlexHlsSink>);
+
+impl SignalStorage {
+    fn emit(&self, signal: &str, location: &str) -> std::io::Result<Option<glib::Value>> {
+        let element = self
+            .0
+            .upgrade()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "The element is gone"))?;
+        element
+            .emit_by_name(signal, &[&location])
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))
+    }
+
+    fn open(&self, signal: &str, location: &str) -> std::io::Result<gio::OutputStream> {
+        self.emit(signal, location)?
+            .and_then(|stream| stream.get::<gio::OutputStream>().ok())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("No stream to write {}", location),
+                )
+            })
+    }
+}
+
+impl HlsStorage for SignalStorage {
+    fn open_segment(&self, location: &str) -> std::io::Result<gio::OutputStream> {
+        self.open(SIGNAL_GET_FRAGMENT_STREAM, location)
+    }
+
+    fn open_playlist(&self, location: &str) -> std::io::Result<gio::OutputStream> {
+        self.open(SIGNAL_GET_PLAYLIST_STREAM, location)
+    }
+
+    fn commit_playlist(&self, location: &str) -> std::io::Result<()> {
+        let committed = self
+            .emit(SIGNAL_COMMIT_PLAYLIST_STREAM, location)?
+            .and_then(|committed| committed.get::<bool>().ok())
+            .unwrap_or(false);
+        match committed {
+            true => Ok(()),
+            false => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Playlist {} was not committed", location),
+            )),
+        }
+    }
+
+    fn delete(&self, location: &str) -> std::io::Result<()> {
+        self.emit(SIGNAL_DELETE_FRAGMENT, location).map(|_| ())
+    }
+
+    fn list(&self, _prefix: &str) -> std::io::Result<Vec<String>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Files written through the signals can not be listed",
+        ))
+    }
+
+    fn read(&self, _location: &str) -> std::io::Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Files written through the signals can not be read back",
+        ))
+    }
+}
+
 struct Settings {
     location: String,
+    init_location: String,
     playlist_location: String, // TODO: Evaluate the use of `PathBuf` instead.
+    iframe_playlist_location: Option<String>,
+    subtitle_location: String,
+    subtitle_playlist_location: String,
     playlist_root: Option<String>, // TODO: Evaluate the use of `PathBuf` instead.
     playlist_length: u32,
     max_num_segment_files: usize,
     target_duration: u32,
     send_keyframe_requests: bool,
+    segment_format: SegmentFormat,
+    part_duration: u32,
+    encryption: Encryption,
+    key_location: String,
+    key_rotation: u32,
+    program_date_time: ProgramDateTime,
+    playlist_type: PlaylistType,
+    single_file: bool,
+    cue_tags: bool,
+    align_to_epoch: bool,
+    resume: bool,
+    /// Storage set through `FlexHlsSink::set_storage`, used in place of the signals.
+    storage: Option<Arc<dyn HlsStorage>>,
+    upload_url: Option<String>,
+    upload_retries: u32,
+    upload_queue_size: u32,
+    /// Storage uploading to `upload-url`, from start to stop.
+    upload_storage: Option<Arc<HttpStorage>>,
+    write_queue_size: u32,
+    write_queue_full: WriteQueueFull,
+    /// Writer thread used when `write-queue-size` is set, from start to stop.
+    write_queue: Option<Arc<WriteQueue>>,
+    target_duration_policy: TargetDurationPolicy,
 
     splitmuxsink: Option<gst::Element>,
     giostreamsink: Option<gst::Element>,
     video_sink: bool,
-    audio_sink: bool,
+    audio_sinks: usize,
+    next_audio_index: u32,
+    /// Index of the next audio pad requested from `splitmuxsink`, for the audio and meta pads
+    /// alike, whatever the name of the pad requested from the element.
+    next_muxer_audio_index: u32,
+    next_subtitle_index: u32,
+}
+
+impl Settings {
+    /// URI of the file written at `location`, as referenced by the playlist.
+    ///
+    /// Only the file name is kept from `location`, so the path the files are written to does not
+    /// leak into the playlist, and it is prefixed by `playlist-root` when set.
+    fn uri_for(&self, location: &str) -> String {
+        let file_name = path::Path::new(location)
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .unwrap_or(location);
+
+        match &self.playlist_root {
+            Some(playlist_root) => format!("{}/{}", playlist_root.trim_end_matches('/'), file_name),
+            None => file_name.to_string(),
+        }
+    }
+
+    /// Makes an empty media playlist of the configured type.
+    fn new_media_playlist(&self, version: usize, part_target: Option<f32>) -> MediaPlaylist {
+        MediaPlaylist::new(
+            m3u8_rs::playlist::MediaPlaylist {
+                version,
+                target_duration: self.target_duration as f32,
+                media_sequence: 0,
+                segments: vec![],
+                discontinuity_sequence: 0,
+                end_list: false,
+                playlist_type: self.playlist_type.media_playlist_type(),
+                i_frames_only: false,
+                start: None,
+                independent_segments: false,
+                unknown_tags: vec![],
+            },
+            part_target,
+        )
+    }
+
+    /// Removes the segments sliding out of a live playlist.
+    fn trim_playlist(&self, playlist: &mut MediaPlaylist) {
+        let max_playlist_length = self.playlist_length as usize;
+        if self.playlist_type.is_sliding()
+            && max_playlist_length > 0
+            && playlist.segments.len() > max_playlist_length
+        {
+            for _ in 0..playlist.segments.len() - max_playlist_length {
+                let _ = playlist.remove_first_segment();
+            }
+        }
+    }
+
+    /// Duration after which splitmuxsink starts a new segment, segments aligned to the epoch
+    /// are split at the boundaries of its intervals instead.
+    fn max_size_time(&self) -> u64 {
+        match self.align_to_epoch {
+            true => 0,
+            false => self.target_duration as u64 * gst::SECOND_VAL,
+        }
+    }
+
+    /// Storage of the files, `None` when left to the signals. A storage set by the application
+    /// takes precedence over `upload-url`.
+    fn storage(&self) -> Option<Arc<dyn HlsStorage>> {
+        match (&self.storage, &self.upload_storage) {
+            (Some(storage), _) => Some(storage.clone()),
+            (None, Some(upload_storage)) => Some(upload_storage.clone()),
+            (None, None) => None,
+        }
+    }
+
+    /// Source of the wall-clock time of the segments aligned to the epoch.
+    fn wall_clock(&self) -> ProgramDateTime {
+        match self.program_date_time {
+            ProgramDateTime::None => ProgramDateTime::Clock,
+            wall_clock => wall_clock,
+        }
+    }
+
+    /// Configures the `splitmuxsink` muxer for the current segment format.
+    fn configure_muxer(&self) {
+        if let Some(splitmuxsink) = &self.splitmuxsink {
+            let mux = self.segment_format.make_muxer(self.target_duration);
+            splitmuxsink
+                .set_properties(&[
+                    ("muxer", &mux),
+                    ("reset-muxer", &self.segment_format.reset_muxer()),
+                ])
+                .unwrap();
+        }
+    }
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
             location: String::from(DEFAULT_LOCATION),
+            init_location: String::from(DEFAULT_INIT_LOCATION),
             playlist_location: String::from(DEFAULT_PLAYLIST_LOCATION),
+            iframe_playlist_location: None,
+            subtitle_location: String::from(DEFAULT_SUBTITLE_LOCATION),
+            subtitle_playlist_location: String::from(DEFAULT_SUBTITLE_PLAYLIST_LOCATION),
             playlist_root: None,
             playlist_length: DEFAULT_PLAYLIST_LENGTH,
             max_num_segment_files: DEFAULT_MAX_NUM_SEGMENT_FILES as usize,
             target_duration: DEFAULT_TARGET_DURATION,
             send_keyframe_requests: DEFAULT_SEND_KEYFRAME_REQUESTS,
+            segment_format: DEFAULT_SEGMENT_FORMAT,
+            part_duration: DEFAULT_PART_DURATION,
+            encryption: DEFAULT_ENCRYPTION,
+            key_location: String::from(DEFAULT_KEY_LOCATION),
+            key_rotation: DEFAULT_KEY_ROTATION,
+            program_date_time: DEFAULT_PROGRAM_DATE_TIME,
+            playlist_type: DEFAULT_PLAYLIST_TYPE,
+            single_file: DEFAULT_SINGLE_FILE,
+            cue_tags: DEFAULT_CUE_TAGS,
+            align_to_epoch: DEFAULT_ALIGN_TO_EPOCH,
+            resume: DEFAULT_RESUME,
+            storage: None,
+            upload_url: None,
+            upload_retries: DEFAULT_UPLOAD_RETRIES,
+            upload_queue_size: DEFAULT_UPLOAD_QUEUE_SIZE,
+            upload_storage: None,
+            write_queue_size: DEFAULT_WRITE_QUEUE_SIZE,
+            write_queue_full: DEFAULT_WRITE_QUEUE_FULL,
+            write_queue: None,
+            target_duration_policy: DEFAULT_TARGET_DURATION_POLICY,
 
             splitmuxsink: None,
             giostreamsink: None,
             video_sink: false,
-            audio_sink: false,
+            audio_sinks: 0,
+            next_audio_index: 0,
+            next_muxer_audio_index: 0,
+            next_subtitle_index: 0,
         }
     }
 }
@@ -76,263 +491,2617 @@ enum State {
 
         fragment_opened_at: Option<gst::ClockTime>,
         current_segment_location: Option<String>,
+        /// Media sequence number of the current segment.
+        current_segment_sequence: u64,
+        /// Segments left out of the playlist for exceeding its target duration, the segments
+        /// listed after them are numbered without them.
+        unlisted_segments: u64,
         old_segment_locations: Vec<String>,
+
+        /// Number of bytes handed to the sink for the current output file.
+        output_bytes: u64,
+        /// Byte offset of the current segment in the output file.
+        segment_offset: u64,
+        /// Location and stream of the output file when writing all segments to a single file.
+        single_file_stream: Option<(String, gio::OutputStream)>,
+
+        init_segment: InitSegment,
+        /// ID3 timestamp tag to write before the first buffer of the current packed audio segment.
+        pending_timestamp_tag: Option<Vec<u8>>,
+        part_tracker: PartTracker,
+        keyframe_tracker: KeyframeTracker,
+
+        encryption_key: Option<EncryptionKey>,
+        /// Index of the first key, past the ones of a resumed playlist.
+        first_key_index: u32,
+        current_segment_key: Option<Key>,
+        /// Location of the key of each encrypted segment still stored, so keys are deleted
+        /// along with the last of their segments.
+        segment_key_locations: HashMap<String, String>,
+
+        /// Running time of a discontinuity found in the input, the first segment starting at or
+        /// after it is marked with `EXT-X-DISCONTINUITY`.
+        discontinuity_pending: Option<u64>,
+        current_segment_discontinuity: bool,
+
+        /// Latest running time of the input with a known wall-clock time, in nanoseconds.
+        reference_timestamp: Option<ReferenceTimestamp>,
+        current_segment_date_time: Option<String>,
+
+        /// A new segment was requested and has not started yet.
+        split_requested: bool,
+        /// Running time of the last split requested at an interval of the epoch.
+        next_aligned_split: Option<u64>,
+        /// Splice points waiting for the segment starting at them.
+        pending_splices: Vec<Splice>,
+        current_segment_splice: Option<Splice>,
+        /// Start date of the ad breaks in progress, by splice event ID.
+        splice_start_dates: HashMap<u32, String>,
     },
 }
 
+/// Wall-clock time of a buffer, as told by its `GstReferenceTimestampMeta`.
+#[derive(Clone, Copy)]
+struct ReferenceTimestamp {
+    running_time: u64,
+    unix_time: u64,
+}
+
+/// Wall-clock time of the pipeline clock, mapping the running times to the UNIX time.
+struct ClockCalibration {
+    clock: gst::Clock,
+    /// UNIX time at the time zero of the clock, in nanoseconds.
+    offset: i64,
+}
+
+/// Key used to encrypt the segments until the next key rotation.
+struct EncryptionKey {
+    index: u32,
+    bytes: [u8; KEY_LENGTH],
+    location: String,
+    uri: String,
+    segments: u32,
+}
+
+/// Tracks the partial segment being written when producing Low-Latency HLS playlists.
+#[derive(Default)]
+struct PartTracker {
+    /// Byte offset in the output file of the end of the current segment handed to the sink.
+    segment_bytes: u64,
+    /// Byte offset of the current part in the output file.
+    part_offset: u64,
+    part_started_at: Option<u64>,
+    part_independent: bool,
+}
+
+impl PartTracker {
+    /// Tracks the parts of a segment starting at `offset` of the output file.
+    fn starting_at(offset: u64) -> Self {
+        Self {
+            segment_bytes: offset,
+            part_offset: offset,
+            ..Default::default()
+        }
+    }
+
+    /// Accounts for a muxed buffer of the current segment, returning the part it completes.
+    fn push(
+        &mut self,
+        uri: &str,
+        timestamp: Option<u64>,
+        size: u64,
+        independent: bool,
+        part_duration: u64,
+    ) -> Option<PartialSegment> {
+        let mut completed_part = None;
+        if let Some(timestamp) = timestamp {
+            match self.part_started_at {
+                Some(started_at) if timestamp.saturating_sub(started_at) >= part_duration => {
+                    let duration = (timestamp - started_at) as f32 / gst::SECOND_VAL as f32;
+                    completed_part = Some(self.close_part(uri, duration));
+                    self.part_started_at = Some(timestamp);
+                }
+                Some(_) => (),
+                None => self.part_started_at = Some(timestamp),
+            }
+        }
+
+        self.part_independent |= independent;
+        self.segment_bytes += size;
+        completed_part
+    }
+
+    /// Closes the last part of the segment, which lasts until the end of the segment.
+    fn finish(&mut self, uri: &str, duration: f32) -> Option<PartialSegment> {
+        self.part_started_at.take()?;
+        Some(self.close_part(uri, duration.max(0.0)))
+    }
+
+    fn close_part(&mut self, uri: &str, duration: f32) -> PartialSegment {
+        let part = PartialSegment {
+            uri: uri.to_string(),
+            duration,
+            offset: self.part_offset,
+            length: self.segment_bytes - self.part_offset,
+            independent: self.part_independent,
+        };
+        self.part_offset = self.segment_bytes;
+        self.part_independent = false;
+        part
+    }
+}
+
+/// Watches the data flowing into a sink pad for discontinuities of the stream.
+#[derive(Default)]
+struct DiscontinuityDetector {
+    caps: Option<gst::Caps>,
+    segment: Option<gst::FormattedSegment<gst::ClockTime>>,
+    last_timestamp: Option<u64>,
+}
+
+impl DiscontinuityDetector {
+    /// Returns the running time the stream is discontinuous at, when it is at the given buffer
+    /// or event, the timestamps of consecutive buffers being allowed to jump by up to `max_gap`
+    /// nanoseconds. Events have no running time of their own, the next segment starts after
+    /// them whatever its running time (0).
+    fn check(&mut self, data: &gst::PadProbeData, max_gap: u64) -> Option<u64> {
+        let discontinuous = match data {
+            gst::PadProbeData::Buffer(buffer) => {
+                let timestamp = buffer.dts_or_pts().nseconds();
+                let discontinuous = match (self.last_timestamp, timestamp) {
+                    (Some(last_timestamp), Some(timestamp)) => {
+                        buffer.flags().contains(gst::BufferFlags::DISCONT)
+                            || timestamp < last_timestamp
+                            || timestamp - last_timestamp > max_gap
+                    }
+                    (Some(_), None) => buffer.flags().contains(gst::BufferFlags::DISCONT),
+                    // The start of the stream is not a discontinuity.
+                    (None, _) => false,
+                };
+                if timestamp.is_some() {
+                    self.last_timestamp = timestamp;
+                }
+                if discontinuous {
+                    let running_time = self
+                        .segment
+                        .as_ref()
+                        .and_then(|segment| segment.to_running_time(buffer.dts_or_pts()).nseconds())
+                        .unwrap_or(0);
+                    return Some(running_time);
+                }
+                false
+            }
+            gst::PadProbeData::Event(event) => match event.view() {
+                gst::EventView::FlushStop(_) => self.last_timestamp.take().is_some(),
+                // A gap only accounts for missing data, the stream is discontinuous when the
+                // next buffer does not follow it.
+                gst::EventView::Gap(gap) => {
+                    let (timestamp, duration) = gap.get();
+                    if let (Some(last_timestamp), Some(timestamp)) =
+                        (self.last_timestamp, timestamp.nseconds())
+                    {
+                        let end = timestamp + duration.nseconds().unwrap_or(0);
+                        self.last_timestamp = Some(last_timestamp.max(end));
+                    }
+                    false
+                }
+                gst::EventView::Caps(caps) => {
+                    let caps = caps.caps_owned();
+                    let changed = matches!(&self.caps, Some(old_caps) if *old_caps != caps);
+                    self.caps = Some(caps);
+                    changed
+                }
+                gst::EventView::Segment(segment) => {
+                    self.segment = segment.segment().downcast_ref::<gst::ClockTime>().cloned();
+                    false
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        match discontinuous {
+            true => Some(0),
+            false => None,
+        }
+    }
+}
+
+/// Reads the wall-clock time of the buffers flowing into a sink pad from their
+/// `GstReferenceTimestampMeta`.
+#[derive(Default)]
+struct ReferenceTimestampReader {
+    segment: Option<gst::FormattedSegment<gst::ClockTime>>,
+}
+
+impl ReferenceTimestampReader {
+    fn read(&mut self, data: &gst::PadProbeData) -> Option<ReferenceTimestamp> {
+        match data {
+            gst::PadProbeData::Buffer(buffer) => {
+                let meta = buffer.meta::<gst::ReferenceTimestampMeta>()?;
+                let timestamp = meta.timestamp().nseconds()?;
+                let unix_time = match meta.reference().structure(0)?.name() {
+                    "timestamp/x-unix" => timestamp,
+                    "timestamp/x-ntp" => {
+                        timestamp.checked_sub(NTP_UNIX_OFFSET * gst::SECOND_VAL)?
+                    }
+                    _ => return None,
+                };
+                let running_time = self
+                    .segment
+                    .as_ref()?
+                    .to_running_time(buffer.pts())
+                    .nseconds()?;
+
+                Some(ReferenceTimestamp {
+                    running_time,
+                    unix_time,
+                })
+            }
+            gst::PadProbeData::Event(event) => {
+                if let gst::EventView::Segment(segment) = event.view() {
+                    self.segment = segment.segment().downcast_ref::<gst::ClockTime>().cloned();
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Element message posted for each version of a playlist written, holding its content.
+fn playlist_updated_message(
+    element: &super::FlexHlsSink,
+    location: &str,
+    content: &[u8],
+) -> gst::Message {
+    let structure = gst::Structure::builder("hls-playlist-updated")
+        .field("location", &location)
+        .field("playlist", &String::from_utf8_lossy(content).as_ref())
+        .build();
+    gst::message::Element::builder(structure)
+        .src(element)
+        .build()
+}
+
+/// Running time of the first buffer of a sample.
+fn sample_running_time(sample: &gst::Sample) -> Option<u64> {
+    let buffer = sample.buffer()?;
+    sample
+        .segment()?
+        .downcast_ref::<gst::ClockTime>()?
+        .to_running_time(buffer.dts_or_pts())
+        .nseconds()
+}
+
+fn is_split_now_event(event: &gst::EventRef) -> bool {
+    event
+        .structure()
+        .map_or(false, |s| s.name() == SPLIT_NOW_EVENT)
+}
+
+/// SCTE-35 splice point (`splice_insert` or `time_signal`), received as a `scte35-splice` custom
+/// downstream event.
+#[derive(Clone, Debug)]
+struct Splice {
+    event_id: u32,
+    /// Whether the splice starts an ad break, or ends it.
+    out_of_network: bool,
+    running_time: u64,
+    duration: Option<u64>,
+    /// The binary `splice_info_section`.
+    splice_info: Option<glib::Bytes>,
+}
+
+impl Splice {
+    /// Reads the splice point of an event, the splice happens at `position` unless the event
+    /// carries a `running-time`.
+    fn from_event(event: &gst::EventRef, position: Option<u64>) -> Option<Self> {
+        let s = event.structure()?;
+        if s.name() != SCTE35_SPLICE_EVENT {
+            return None;
+        }
+
+        Some(Self {
+            event_id: s.get::<u32>("event-id").ok()?,
+            out_of_network: s.get::<bool>("out-of-network").ok()?,
+            running_time: s
+                .get::<gst::ClockTime>("running-time")
+                .ok()
+                .and_then(|time| time.nseconds())
+                .or(position)?,
+            duration: s
+                .get::<gst::ClockTime>("duration")
+                .ok()
+                .and_then(|time| time.nseconds()),
+            splice_info: s.get::<glib::Bytes>("splice-info").ok(),
+        })
+    }
+
+    fn is_same_as(&self, other: &Splice) -> bool {
+        self.event_id == other.event_id && self.out_of_network == other.out_of_network
+    }
+
+    /// Attributes of the `EXT-X-DATERANGE` of the splice point, in the segment starting at
+    /// `start_date`. The end of an ad break closes the date range of its start, starting at
+    /// `out_start_date`, which is required to share its ID.
+    fn daterange(&self, start_date: &str, out_start_date: Option<&str>) -> String {
+        let mut daterange = match (self.out_of_network, out_start_date) {
+            (false, Some(out_start_date)) => format!(
+                "ID=\"splice-{}\",START-DATE=\"{}\",END-DATE=\"{}\"",
+                self.event_id, out_start_date, start_date
+            ),
+            // The start of the ad break is not known, e.g. it was before a restart.
+            (false, None) => format!(
+                "ID=\"splice-{}-in\",START-DATE=\"{}\"",
+                self.event_id, start_date
+            ),
+            (true, _) => format!(
+                "ID=\"splice-{}\",START-DATE=\"{}\"",
+                self.event_id, start_date
+            ),
+        };
+        if let (true, Some(duration)) = (self.out_of_network, self.duration) {
+            daterange.push_str(&format!(
+                ",PLANNED-DURATION={:.3}",
+                duration as f64 / gst::SECOND_VAL as f64
+            ));
+        }
+        if let Some(splice_info) = &self.splice_info {
+            let hex = splice_info
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<String>();
+            let attribute = match self.out_of_network {
+                true => "SCTE35-OUT",
+                false => "SCTE35-IN",
+            };
+            daterange.push_str(&format!(",{}=0x{}", attribute, hex));
+        }
+        daterange
+    }
+
+    fn cue_tag(&self) -> CueTag {
+        match self.out_of_network {
+            true => CueTag::Out(
+                self.duration
+                    .map(|duration| duration as f32 / gst::SECOND_VAL as f32),
+            ),
+            false => CueTag::In,
+        }
+    }
+}
+
+/// Tracks the running time of the buffers of a stream.
+#[derive(Default)]
+struct RunningTimeTracker {
+    segment: Option<gst::FormattedSegment<gst::ClockTime>>,
+}
+
+impl RunningTimeTracker {
+    fn running_time(&mut self, data: &gst::PadProbeData) -> Option<u64> {
+        match data {
+            gst::PadProbeData::Buffer(buffer) => self
+                .segment
+                .as_ref()?
+                .to_running_time(buffer.dts_or_pts())
+                .nseconds(),
+            gst::PadProbeData::Event(event) => {
+                if let gst::EventView::Segment(segment) = event.view() {
+                    self.segment = segment.segment().downcast_ref::<gst::ClockTime>().cloned();
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Keyframe {
+    offset: u64,
+    length: u64,
+    timestamp: Option<u64>,
+}
+
+/// Tracks the keyframes of the segment being written, for the I-frame playlist.
+#[derive(Default)]
+struct KeyframeTracker {
+    keyframes: Vec<Keyframe>,
+    /// Whether the last buffer handed to the sink belongs to a keyframe.
+    in_keyframe: bool,
+}
+
+impl KeyframeTracker {
+    /// Accounts for a muxed buffer at `offset` of the output file.
+    fn push(&mut self, offset: u64, size: u64, timestamp: Option<u64>, keyframe: bool) {
+        if !keyframe {
+            self.in_keyframe = false;
+            return;
+        }
+
+        // A keyframe can be split over several consecutive buffers by the muxer.
+        match self.keyframes.last_mut() {
+            Some(last) if self.in_keyframe => last.length += size,
+            _ => self.keyframes.push(Keyframe {
+                offset,
+                length: size,
+                timestamp,
+            }),
+        }
+        self.in_keyframe = true;
+    }
+
+    /// Returns the keyframes of the segment lasting `duration` seconds that was just completed.
+    fn finish(&mut self, duration: f32) -> Vec<IFrame> {
+        let keyframes = std::mem::take(&mut self.keyframes);
+        self.in_keyframe = false;
+
+        let segment_start = keyframes.first().and_then(|keyframe| keyframe.timestamp);
+        keyframes
+            .iter()
+            .enumerate()
+            .map(|(idx, keyframe)| {
+                let next = keyframes.get(idx + 1).and_then(|next| next.timestamp);
+                let duration = match (keyframe.timestamp, next, segment_start) {
+                    (Some(timestamp), Some(next), _) => {
+                        next.saturating_sub(timestamp) as f32 / gst::SECOND_VAL as f32
+                    }
+                    // The last keyframe lasts until the end of the segment.
+                    (Some(timestamp), None, Some(segment_start)) => {
+                        duration
+                            - timestamp.saturating_sub(segment_start) as f32
+                                / gst::SECOND_VAL as f32
+                    }
+                    _ => 0.0,
+                };
+                IFrame {
+                    offset: keyframe.offset,
+                    length: keyframe.length,
+                    duration: duration.max(0.0),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Tracks the ISO-BMFF header (`ftyp` + `moov`) produced by the fragmented MP4 muxer.
+enum InitSegment {
+    /// Header boxes are being collected from the first fragment.
+    Pending(Vec<u8>),
+    /// The init segment was written and is referenced by `EXT-X-MAP`.
+    Written(String),
+}
+
 impl Default for State {
     fn default() -> Self {
         Self::Stopped
     }
-}
+}
+
+/// Timed ID3 metadata muxed into the segments from the `meta` pad. Unless the pad is linked
+/// upstream, the stream is fed with the tags of `insert-metadata`.
+struct MetaStream {
+    pad: gst::GhostPad,
+    /// Running time the stream was pushed up to, in nanoseconds.
+    position: Option<u64>,
+}
+
+/// Subtitles received on a `subtitle_%u` pad, segmented on the boundaries of the video segments.
+struct SubtitleTrack {
+    pad: gst::Pad,
+    location: String,
+    playlist_location: String,
+    segment: Option<gst::FormattedSegment<gst::ClockTime>>,
+    cues: Vec<Cue>,
+    playlist: MediaPlaylist,
+    segment_index: u32,
+    old_segment_locations: Vec<String>,
+}
+
+/// Files of a subtitle track to write, once no lock is held anymore.
+#[derive(Default)]
+struct SubtitleOutput {
+    segment: Option<(String, Vec<u8>)>,
+    playlist: Option<(String, Vec<u8>)>,
+    deleted_segment_locations: Vec<String>,
+}
+
+impl SubtitleTrack {
+    fn new(pad: gst::Pad, index: u32, settings: &Settings) -> Self {
+        let index = index.to_string();
+        Self {
+            pad,
+            location: settings
+                .subtitle_location
+                .replace(PAD_INDEX_PLACEHOLDER, &index),
+            playlist_location: settings
+                .subtitle_playlist_location
+                .replace(PAD_INDEX_PLACEHOLDER, &index),
+            segment: None,
+            cues: vec![],
+            playlist: settings.new_media_playlist(GST_M3U8_PLAYLIST_VERSION, None),
+            segment_index: 0,
+            old_segment_locations: vec![],
+        }
+    }
+
+    fn reset(&mut self, settings: &Settings) {
+        self.cues.clear();
+        self.playlist = settings.new_media_playlist(GST_M3U8_PLAYLIST_VERSION, None);
+        self.segment_index = 0;
+        self.old_segment_locations.clear();
+    }
+
+    /// Renders the WebVTT segment between the running times `start` and `end`.
+    fn complete_segment(&mut self, settings: &Settings, start: u64, end: u64) -> (String, Vec<u8>) {
+        let location = self.location.replace(
+            BACKWARDS_COMPATIBLE_PLACEHOLDER,
+            &format!("{:0>5}", self.segment_index),
+        );
+
+        // Cues overlapping the segment boundaries are repeated in each segment.
+        let cues = self
+            .cues
+            .iter()
+            .filter(|cue| cue.start < end && cue.end > start)
+            .cloned()
+            .collect::<Vec<_>>();
+        self.cues.retain(|cue| cue.end > end);
+
+        let mut content = vec![];
+        webvtt::write_segment(
+            &mut content,
+            &cues,
+            settings.segment_format.mpegts_timestamp_offset(),
+        )
+        .unwrap();
+
+        self.playlist.push_segment(
+            MediaSegment {
+                uri: settings.uri_for(&location),
+                duration: (end - start) as f32 / gst::SECOND_VAL as f32,
+                title: None,
+                byte_range: None,
+                discontinuity: false,
+                key: None,
+                map: None,
+                program_date_time: None,
+                daterange: None,
+            },
+            self.segment_index as u64,
+            vec![],
+            vec![],
+            None,
+        );
+        self.segment_index += 1;
+        self.old_segment_locations.push(location.clone());
+
+        (location, content)
+    }
+
+    /// Renders the playlist, returning `None` while a VOD playlist is not complete.
+    fn render_playlist(&mut self, settings: &Settings) -> Option<Vec<u8>> {
+        settings.trim_playlist(&mut self.playlist);
+
+        if settings.playlist_type == PlaylistType::Vod && !self.playlist.end_list {
+            return None;
+        }
+        let mut content = vec![];
+        self.playlist.write_to(&mut content).unwrap();
+        Some(content)
+    }
+
+    fn take_deleted_segment_locations(&mut self, settings: &Settings) -> Vec<String> {
+        let max_num_segments = settings.max_num_segment_files;
+        if !settings.playlist_type.is_sliding()
+            || self.old_segment_locations.len() <= max_num_segments
+        {
+            return vec![];
+        }
+        self.old_segment_locations
+            .drain(..self.old_segment_locations.len() - max_num_segments)
+            .collect()
+    }
+}
+
+/// Statistics of the output since the element started, exposed by the `stats` property.
+#[derive(Default)]
+struct Stats {
+    segments_written: u64,
+    segments_deleted: u64,
+    /// Bytes of the segments written.
+    bytes_written: u64,
+    /// Bitrate of the last segment, in bits per second.
+    bitrate: u64,
+    peak_bitrate: u64,
+    min_segment_duration: Option<gst::ClockTime>,
+    max_segment_duration: Option<gst::ClockTime>,
+    total_segment_duration: u64,
+    playlists_written: u64,
+    playlist_write_failures: u64,
+    /// Segments whose duration, rounded to the nearest second, exceeds the target duration of
+    /// the playlist.
+    target_duration_violations: u64,
+}
+
+impl Stats {
+    fn add_segment(&mut self, size: u64, duration: gst::ClockTime, exceeds_target_duration: bool) {
+        self.segments_written += 1;
+        self.bytes_written += size;
+
+        let duration_ns = duration.nseconds().unwrap_or(0);
+        if duration_ns > 0 {
+            self.bitrate = size * 8 * gst::SECOND_VAL / duration_ns;
+            self.peak_bitrate = self.peak_bitrate.max(self.bitrate);
+        }
+        self.min_segment_duration = Some(
+            self.min_segment_duration
+                .map_or(duration, |min| min.min(duration)),
+        );
+        self.max_segment_duration = Some(
+            self.max_segment_duration
+                .map_or(duration, |max| max.max(duration)),
+        );
+        self.total_segment_duration += duration_ns;
+
+        if exceeds_target_duration {
+            self.target_duration_violations += 1;
+        }
+    }
+
+    fn to_structure(&self) -> gst::Structure {
+        // Durations are `GST_CLOCK_TIME_NONE` until a segment is written.
+        let average_segment_duration = match self.segments_written {
+            0 => gst::CLOCK_TIME_NONE,
+            segments_written => {
+                gst::ClockTime::from_nseconds(self.total_segment_duration / segments_written)
+            }
+        };
+        gst::Structure::builder("application/x-flexhlssink-stats")
+            .field("segments-written", &self.segments_written)
+            .field("segments-deleted", &self.segments_deleted)
+            .field("bytes-written", &self.bytes_written)
+            .field("bitrate", &self.bitrate)
+            .field("peak-bitrate", &self.peak_bitrate)
+            .field(
+                "min-segment-duration",
+                &self.min_segment_duration.unwrap_or(gst::CLOCK_TIME_NONE),
+            )
+            .field(
+                "max-segment-duration",
+                &self.max_segment_duration.unwrap_or(gst::CLOCK_TIME_NONE),
+            )
+            .field("average-segment-duration", &average_segment_duration)
+            .field("playlists-written", &self.playlists_written)
+            .field("playlist-write-failures", &self.playlist_write_failures)
+            .field(
+                "target-duration-violations",
+                &self.target_duration_violations,
+            )
+            .build()
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct FlexHlsSink {
+    settings: Arc<Mutex<Settings>>,
+    state: Arc<Mutex<State>>,
+    subtitles: Arc<Mutex<Vec<SubtitleTrack>>>,
+    meta: Arc<Mutex<Option<MetaStream>>>,
+    stats: Arc<Mutex<Stats>>,
+    clock_calibration: Arc<Mutex<Option<ClockCalibration>>>,
+}
+
+impl FlexHlsSink {
+    fn new() -> Self {
+        Self {
+            settings: Arc::new(Mutex::new(Settings::default())),
+            state: Arc::new(Mutex::new(State::default())),
+            subtitles: Arc::new(Mutex::new(vec![])),
+            meta: Arc::new(Mutex::new(None)),
+            stats: Arc::new(Mutex::new(Stats::default())),
+            clock_calibration: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn start(
+        &self,
+        element: &super::FlexHlsSink,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        gst_info!(CAT, obj: element, "Starting");
+        *self.stats.lock().unwrap() = Stats::default();
+        *self.clock_calibration.lock().unwrap() = None;
+
+        let (playlist, playlist_index, old_segment_locations) = {
+            let mut settings = self.settings.lock().unwrap();
+            // Whole segments are encrypted, players can not decrypt an init segment listed
+            // after the key nor fetch a byte range of a segment on its own.
+            let unencryptable = if settings.single_file {
+                Some("Segments written to a single file")
+            } else if settings.segment_format == SegmentFormat::Fmp4 {
+                Some("Fragmented MP4 segments")
+            } else if settings.iframe_playlist_location.is_some() {
+                Some("Segments listed in an I-frame playlist")
+            } else if settings.part_duration > 0 {
+                Some("Segments split into partial segments")
+            } else {
+                None
+            };
+            if let (Some(segments), Encryption::Aes128) = (unencryptable, settings.encryption) {
+                let error_msg = gst::error_msg!(
+                    gst::LibraryError::Settings,
+                    ["{} can not be encrypted", segments]
+                );
+                element.post_error_message(error_msg);
+                return Err(gst::StateChangeError);
+            }
+            if settings.single_file
+                && settings.write_queue_size > 0
+                && settings.write_queue_full == WriteQueueFull::DropSegment
+            {
+                let error_msg = gst::error_msg!(
+                    gst::LibraryError::Settings,
+                    ["Segments written to a single file can not be dropped"]
+                );
+                element.post_error_message(error_msg);
+                return Err(gst::StateChangeError);
+            }
+            if settings.align_to_epoch && settings.target_duration == 0 {
+                let error_msg = gst::error_msg!(
+                    gst::LibraryError::Settings,
+                    ["Segments can only be aligned to the epoch with a target duration"]
+                );
+                element.post_error_message(error_msg);
+                return Err(gst::StateChangeError);
+            }
+            if settings.segment_format == SegmentFormat::PackedAudio && settings.video_sink {
+                let error_msg = gst::error_msg!(
+                    gst::LibraryError::Settings,
+                    ["Packed audio segments can not hold a video stream"]
+                );
+                element.post_error_message(error_msg);
+                return Err(gst::StateChangeError);
+            }
+
+            settings.upload_storage = match &settings.upload_url {
+                Some(upload_url) => match HttpStorage::new(
+                    upload_url,
+                    settings.upload_retries,
+                    settings.upload_queue_size as usize,
+                ) {
+                    Ok(upload_storage) => Some(Arc::new(upload_storage)),
+                    Err(err) => {
+                        let error_msg = gst::error_msg!(
+                            gst::ResourceError::Failed,
+                            ["Could not start the upload thread: {}", err]
+                        );
+                        element.post_error_message(error_msg);
+                        return Err(gst::StateChangeError);
+                    }
+                },
+                None => None,
+            };
+            settings.write_queue = match settings.write_queue_size {
+                0 => None,
+                write_queue_size => {
+                    let storage = settings
+                        .storage()
+                        .unwrap_or_else(|| Arc::new(SignalStorage(element.downgrade())));
+                    match WriteQueue::new(
+                        storage,
+                        write_queue_size as usize,
+                        settings.write_queue_full,
+                    ) {
+                        Ok(write_queue) => Some(Arc::new(write_queue)),
+                        Err(err) => {
+                            settings.upload_storage = None;
+                            let error_msg = gst::error_msg!(
+                                gst::ResourceError::Failed,
+                                ["Could not start the writer thread: {}", err]
+                            );
+                            element.post_error_message(error_msg);
+                            return Err(gst::StateChangeError);
+                        }
+                    }
+                }
+            };
+
+            for track in self.subtitles.lock().unwrap().iter_mut() {
+                track.reset(&settings);
+            }
+            if let Some(meta) = &mut *self.meta.lock().unwrap() {
+                meta.position = None;
+            }
+
+            let version = settings.segment_format.playlist_version();
+            let mut playlist = settings.new_media_playlist(
+                match settings.single_file {
+                    true => version.max(GST_M3U8_PLAYLIST_BYTERANGE_VERSION),
+                    false => version,
+                },
+                match settings.part_duration {
+                    0 => None,
+                    part_duration => Some(part_duration as f32 / 1_000f32),
+                },
+            );
+
+            let old_segment_locations = match settings.resume {
+                true => match self.resume_playlist(element, &settings, &mut playlist) {
+                    Ok(old_segment_locations) => old_segment_locations,
+                    Err(error_msg) => {
+                        settings.write_queue = None;
+                        settings.upload_storage = None;
+                        element.post_error_message(error_msg);
+                        return Err(gst::StateChangeError);
+                    }
+                },
+                false => vec![],
+            };
+            let playlist_index = playlist.media_sequence as usize + playlist.segments.len();
+            // The segments of the resumed playlist are not overwritten by the new ones.
+            if let Some(splitmuxsink) = &settings.splitmuxsink {
+                splitmuxsink
+                    .set_property("start-index", &(playlist_index as i32))
+                    .unwrap();
+            }
+
+            (playlist, playlist_index, old_segment_locations)
+        };
+
+        let mut state = self.state.lock().unwrap();
+        if let State::Stopped = *state {
+            *state = State::Started {
+                playlist,
+                playlist_render_state: PlaylistRenderState::Init,
+                playlist_index,
+                current_segment_location: None,
+                current_segment_sequence: playlist_index as u64,
+                unlisted_segments: 0,
+                fragment_opened_at: None,
+                old_segment_locations,
+                output_bytes: 0,
+                segment_offset: 0,
+                single_file_stream: None,
+                init_segment: InitSegment::Pending(vec![]),
+                pending_timestamp_tag: None,
+                part_tracker: PartTracker::default(),
+                keyframe_tracker: KeyframeTracker::default(),
+                encryption_key: None,
+                first_key_index: playlist_index as u32,
+                current_segment_key: None,
+                segment_key_locations: HashMap::new(),
+                // The first segment after a restart does not follow the resumed ones.
+                discontinuity_pending: match playlist_index {
+                    0 => None,
+                    _ => Some(0),
+                },
+                current_segment_discontinuity: false,
+                reference_timestamp: None,
+                current_segment_date_time: None,
+                split_requested: false,
+                next_aligned_split: None,
+                pending_splices: vec![],
+                current_segment_splice: None,
+                splice_start_dates: HashMap::new(),
+            };
+        }
+
+        Ok(gst::StateChangeSuccess::Success)
+    }
+
+    /// Restores the segments of the playlist at `playlist-location`, returns the locations of
+    /// their files. Fails when the playlist can not be read back, rather than overwriting it.
+    fn resume_playlist(
+        &self,
+        element: &super::FlexHlsSink,
+        settings: &Settings,
+        playlist: &mut MediaPlaylist,
+    ) -> Result<Vec<String>, gst::ErrorMessage> {
+        // Without a storage, the playlist is where the default handlers of the signals write it.
+        let content = match settings.storage() {
+            Some(storage) => storage.read(&settings.playlist_location),
+            None => FsStorage.read(&settings.playlist_location),
+        };
+        let content = match content {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                gst_info!(
+                    CAT,
+                    obj: element,
+                    "No playlist to resume at {}: {}",
+                    settings.playlist_location,
+                    err
+                );
+                return Ok(vec![]);
+            }
+            Err(err) => {
+                return Err(gst::error_msg!(
+                    gst::ResourceError::Read,
+                    [
+                        "Could not read the playlist to resume at {}: {}",
+                        settings.playlist_location,
+                        err
+                    ]
+                ))
+            }
+        };
+        let resumed = match m3u8_rs::parse_media_playlist_res(&content) {
+            Ok(resumed) => resumed,
+            Err(err) => {
+                gst_warning!(
+                    CAT,
+                    obj: element,
+                    "Could not parse the playlist to resume at {}: {:?}",
+                    settings.playlist_location,
+                    err
+                );
+                return Ok(vec![]);
+            }
+        };
+
+        gst_info!(
+            CAT,
+            obj: element,
+            "Resuming {} segments from sequence {}",
+            resumed.segments.len(),
+            resumed.media_sequence
+        );
+        playlist.media_sequence = resumed.media_sequence;
+        playlist.discontinuity_sequence = resumed.discontinuity_sequence;
+
+        let mut old_segment_locations = vec![];
+        for (idx, segment) in resumed.segments.into_iter().enumerate() {
+            // The files are next to the location of the new segments, under the same name.
+            let file_name = segment.uri.rsplit('/').next().unwrap_or(&segment.uri);
+            let location = path::Path::new(&settings.location)
+                .with_file_name(file_name)
+                .to_string_lossy()
+                .into_owned();
+            // The single output file is never deleted.
+            if !settings.single_file && !old_segment_locations.contains(&location) {
+                old_segment_locations.push(location);
+            }
+            let sequence = resumed.media_sequence as u64 + idx as u64;
+            playlist.push_segment(segment, sequence, vec![], vec![], None);
+        }
+
+        Ok(old_segment_locations)
+    }
+
+    fn on_format_location(
+        &self,
+        element: &super::FlexHlsSink,
+        fragment_id: u32,
+        first_sample: Option<gst::Sample>,
+    ) -> Result<String, String> {
+        gst_info!(
+            CAT,
+            "Starting the formatting of the fragment-id: {}",
+            fragment_id
+        );
+
+        let (
+            location,
+            encryption,
+            key_location,
+            key_rotation,
+            single_file,
+            giostreamsink,
+            epoch_interval,
+            wall_clock,
+        ) = {
+            let settings = self.settings.lock().unwrap();
+            (
+                settings.location.clone(),
+                settings.encryption,
+                settings.key_location.clone(),
+                settings.key_rotation,
+                settings.single_file,
+                settings.giostreamsink.clone().unwrap(),
+                match (settings.align_to_epoch, settings.target_duration) {
+                    (true, target_duration) if target_duration > 0 => {
+                        Some(target_duration as u64 * gst::SECOND_VAL)
+                    }
+                    _ => None,
+                },
+                settings.wall_clock(),
+            )
+        };
+
+        // The signals of the segment stream and of the key are emitted once the state is
+        // unlocked, so their handlers can read the properties of the element.
+        let (sequence, rotate_to) = {
+            let mut state = self.state.lock().unwrap();
+            match &mut *state {
+                State::Stopped => return Err("Not in Started state".to_string()),
+                State::Started {
+                    current_segment_location,
+                    encryption_key,
+                    single_file_stream,
+                    discontinuity_pending,
+                    current_segment_discontinuity,
+                    playlist_index,
+                    current_segment_sequence,
+                    reference_timestamp,
+                    first_key_index,
+                    ..
+                } => {
+                    // Segments queued before the discontinuity may still be opened after it.
+                    let starts_at = first_sample.as_ref().and_then(sample_running_time);
+                    *current_segment_discontinuity = match (*discontinuity_pending, starts_at) {
+                        (Some(discontinuous_at), Some(starts_at)) => starts_at >= discontinuous_at,
+                        (Some(_), None) => true,
+                        (None, _) => false,
+                    };
+                    if *current_segment_discontinuity {
+                        *discontinuity_pending = None;
+                    }
+
+                    // Segments aligned to the epoch are numbered after the interval they start
+                    // in, so encoders sharing the same wall clock name them alike.
+                    let sequence = match epoch_interval {
+                        Some(epoch_interval) => {
+                            let interval = starts_at
+                                .and_then(|running_time| {
+                                    self.unix_time(
+                                        element,
+                                        wall_clock,
+                                        running_time,
+                                        *reference_timestamp,
+                                    )
+                                })
+                                .map(|unix_time| unix_time as u64 / epoch_interval);
+                            if let Some(interval) = interval {
+                                *playlist_index = (*playlist_index).max(interval as usize);
+                            }
+                            *playlist_index as u64
+                        }
+                        None => fragment_id as u64,
+                    };
+                    *current_segment_sequence = sequence;
+
+                    // All segments are appended to the output file opened for the first one.
+                    if let (true, Some((location, stream))) =
+                        (single_file, single_file_stream.as_ref())
+                    {
+                        *current_segment_location = Some(location.clone());
+                        let (location, stream) = (location.clone(), stream.clone());
+                        drop(state);
+                        giostreamsink.set_property("stream", &stream).unwrap();
+
+                        gst_info!(CAT, "New segment appended to {}", location);
+                        return Ok(location);
+                    }
+
+                    let rotate_to = match (encryption, encryption_key.as_ref()) {
+                        (Encryption::None, _) => None,
+                        (Encryption::Aes128, None) => Some(*first_key_index),
+                        (Encryption::Aes128, Some(key))
+                            if key_rotation > 0 && key.segments >= key_rotation =>
+                        {
+                            Some(key.index + 1)
+                        }
+                        (Encryption::Aes128, Some(_)) => None,
+                    };
+
+                    (sequence, rotate_to)
+                }
+            }
+        };
+
+        let seq_num = format!("{:0>5}", sequence);
+        let segment_file_location = location.replace(BACKWARDS_COMPATIBLE_PLACEHOLDER, &seq_num);
+        gst_trace!(CAT, "Segment location formatted: {}", segment_file_location);
+
+        let fragment_stream = self.open_segment_stream(element, &segment_file_location, true)?;
+        let new_key = match rotate_to {
+            Some(index) => Some(self.rotate_key(element, index, &key_location)?),
+            None => None,
+        };
+
+        let fragment_stream = {
+            let mut state = self.state.lock().unwrap();
+            let (
+                current_segment_location,
+                encryption_key,
+                current_segment_key,
+                segment_key_locations,
+                single_file_stream,
+            ) = match &mut *state {
+                State::Stopped => return Err("Not in Started state".to_string()),
+                State::Started {
+                    current_segment_location,
+                    encryption_key,
+                    current_segment_key,
+                    segment_key_locations,
+                    single_file_stream,
+                    ..
+                } => (
+                    current_segment_location,
+                    encryption_key,
+                    current_segment_key,
+                    segment_key_locations,
+                    single_file_stream,
+                ),
+            };
+
+            *current_segment_location = Some(segment_file_location.clone());
+            if single_file {
+                *single_file_stream =
+                    Some((segment_file_location.clone(), fragment_stream.clone()));
+            }
+
+            match encryption {
+                Encryption::None => fragment_stream,
+                Encryption::Aes128 => {
+                    if let Some(new_key) = new_key {
+                        *encryption_key = Some(new_key);
+                    }
+                    let key = encryption_key.as_mut().unwrap();
+                    key.segments += 1;
+                    segment_key_locations
+                        .insert(segment_file_location.clone(), key.location.clone());
+
+                    // Each segment has its own IV: its sequence number as a 128-bit big-endian
+                    // integer.
+                    let iv = (sequence as u128).to_be_bytes();
+                    *current_segment_key = Some(Key {
+                        method: "AES-128".to_string(),
+                        uri: Some(key.uri.clone()),
+                        iv: Some(format!("0x{:032X}", sequence)),
+                        keyformat: None,
+                        keyformatversions: None,
+                    });
+
+                    AesOutputStream::new(fragment_stream, &key.bytes, &iv).upcast()
+                }
+            }
+        };
+
+        giostreamsink
+            .set_property("stream", &fragment_stream)
+            .unwrap();
+
+        gst_info!(CAT, "New segment location: {}", segment_file_location);
+        Ok(segment_file_location)
+    }
+
+    fn rotate_key(
+        &self,
+        element: &super::FlexHlsSink,
+        index: u32,
+        key_location: &str,
+    ) -> Result<EncryptionKey, String> {
+        let key_location =
+            key_location.replace(BACKWARDS_COMPATIBLE_PLACEHOLDER, &format!("{:0>5}", index));
+
+        let key = element
+            .emit_by_name(SIGNAL_GET_KEY, &[&index, &key_location])
+            .map_err(|err| err.to_string())?
+            .ok_or_else(|| format!("No key returned for key index {}", index))?
+            .get::<glib::Bytes>()
+            .map_err(|err| err.to_string())?;
+        if key.len() != KEY_LENGTH {
+            return Err(format!(
+                "Key must be {} bytes long, got {} bytes",
+                KEY_LENGTH,
+                key.len()
+            ));
+        }
+        let mut bytes = [0u8; KEY_LENGTH];
+        bytes.copy_from_slice(&key);
+
+        let uri = element
+            .emit_by_name(SIGNAL_KEY_ROTATED, &[&index, &key_location])
+            .map_err(|err| err.to_string())?
+            .ok_or_else(|| format!("No URI returned for key index {}", index))?
+            .get::<String>()
+            .map_err(|err| err.to_string())?;
+
+        gst_info!(CAT, obj: element, "Rotated to key {} at {}", index, uri);
+        Ok(EncryptionKey {
+            index,
+            bytes,
+            location: key_location,
+            uri,
+            segments: 0,
+        })
+    }
+
+    /// Generates a random key and stores it at `key_location` using the fragment stream.
+    fn new_key(
+        &self,
+        element: &super::FlexHlsSink,
+        key_location: &str,
+    ) -> Result<glib::Bytes, String> {
+        let key = rand::random::<[u8; KEY_LENGTH]>();
+
+        let mut key_stream = self
+            .open_segment_stream(element, key_location, false)?
+            .into_write();
+        key_stream.write_all(&key).map_err(|err| err.to_string())?;
+        key_stream.flush().map_err(|err| err.to_string())?;
+
+        Ok(glib::Bytes::from_owned(key.to_vec()))
+    }
+
+    fn on_muxed_buffer(
+        &self,
+        element: &super::FlexHlsSink,
+        buffer: &gst::BufferRef,
+    ) -> gst::PadProbeReturn {
+        let (segment_format, init_location, init_uri, part_duration) = {
+            let settings = self.settings.lock().unwrap();
+            (
+                settings.segment_format,
+                settings.init_location.clone(),
+                settings.uri_for(&settings.init_location),
+                settings.part_duration as u64 * gst::MSECOND_VAL,
+            )
+        };
+
+        if segment_format == SegmentFormat::Fmp4
+            && self.split_init_segment(element, buffer, init_location, init_uri)
+        {
+            return gst::PadProbeReturn::Drop;
+        }
+
+        if let State::Started {
+            output_bytes,
+            keyframe_tracker,
+            ..
+        } = &mut *self.state.lock().unwrap()
+        {
+            keyframe_tracker.push(
+                *output_bytes,
+                buffer.size() as u64,
+                buffer.dts_or_pts().nseconds(),
+                !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT),
+            );
+            *output_bytes += buffer.size() as u64;
+        }
+
+        if part_duration > 0 {
+            self.track_part(element, buffer, part_duration);
+        }
+
+        gst::PadProbeReturn::Ok
+    }
+
+    /// Prepends the ID3 timestamp tag to the first buffer of a packed audio segment, as packed
+    /// audio has no container timestamps of its own.
+    fn prepend_timestamp_tag(&self, buffer: &mut gst::Buffer) {
+        let tag = match &mut *self.state.lock().unwrap() {
+            State::Started {
+                pending_timestamp_tag,
+                ..
+            } => pending_timestamp_tag.take(),
+            State::Stopped => None,
+        };
+
+        if let Some(tag) = tag {
+            buffer
+                .make_mut()
+                .prepend_memory(gst::Memory::from_mut_slice(tag));
+        }
+    }
+
+    /// Collects the ISO-BMFF header into the init segment, returns `true` for header buffers.
+    fn split_init_segment(
+        &self,
+        element: &super::FlexHlsSink,
+        buffer: &gst::BufferRef,
+        init_location: String,
+        init_uri: String,
+    ) -> bool {
+        // The muxer pushes each top-level box as its own buffer, so looking at the box type of
+        // the buffer is enough to tell the header apart from the media fragments.
+        let map = match buffer.map_readable() {
+            Ok(map) => map,
+            Err(_) => return false,
+        };
+        let is_header = map.len() >= 8 && matches!(&map[4..8], b"ftyp" | b"moov");
+
+        let header = {
+            let mut state = self.state.lock().unwrap();
+            match &mut *state {
+                State::Stopped => return false,
+                State::Started {
+                    init_segment: InitSegment::Pending(header),
+                    ..
+                } => {
+                    if is_header {
+                        header.extend_from_slice(&map);
+                        return true;
+                    }
+                    std::mem::take(header)
+                }
+                // Every fragment repeats the header, it only belongs in the init segment.
+                State::Started { .. } => return is_header,
+            }
+        };
+
+        match self.write_init_segment(element, &init_location, &header) {
+            Ok(_) => {
+                gst_info!(CAT, obj: element, "Wrote init segment {}", init_location);
+                let mut state = self.state.lock().unwrap();
+                if let State::Started { init_segment, .. } = &mut *state {
+                    *init_segment = InitSegment::Written(init_uri);
+                }
+            }
+            Err(err) => {
+                gst_error!(CAT, obj: element, "Could not write init segment: {}", err);
+            }
+        }
+
+        false
+    }
+
+    /// Splits the segment being written into parts, rewriting the playlist for each new part.
+    fn track_part(
+        &self,
+        element: &super::FlexHlsSink,
+        buffer: &gst::BufferRef,
+        part_duration: u64,
+    ) {
+        let part_completed = {
+            let mut state = self.state.lock().unwrap();
+            match &mut *state {
+                State::Stopped => return,
+                State::Started {
+                    playlist,
+                    part_tracker,
+                    current_segment_location,
+                    ..
+                } => {
+                    let uri = match current_segment_location {
+                        Some(location) => self.settings.lock().unwrap().uri_for(location),
+                        None => return,
+                    };
+                    let part = part_tracker.push(
+                        &uri,
+                        buffer.dts_or_pts().nseconds(),
+                        buffer.size() as u64,
+                        !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT),
+                        part_duration,
+                    );
+                    match part {
+                        Some(part) => {
+                            gst_trace!(CAT, obj: element, "New part of {}", part.uri);
+                            playlist.push_part(part);
+                            playlist.set_preload_hint(Some(PreloadHint {
+                                uri,
+                                offset: part_tracker.part_offset,
+                            }));
+                            true
+                        }
+                        None => false,
+                    }
+                }
+            }
+        };
+
+        if part_completed {
+            if let Err(err) = self.write_playlist(element, None) {
+                gst_error!(CAT, obj: element, "Could not write playlist: {:?}", err);
+            }
+        }
+    }
+
+    fn add_discontinuity_probe(&self, element: &super::FlexHlsSink, pad: &gst::GhostPad) {
+        let this = self.clone();
+        let element_weak = element.downgrade();
+        let detector = Mutex::new(DiscontinuityDetector::default());
+        pad.add_probe(
+            gst::PadProbeType::BUFFER
+                | gst::PadProbeType::EVENT_DOWNSTREAM
+                | gst::PadProbeType::EVENT_FLUSH,
+            move |pad, info| {
+                // Jumps shorter than a segment are not worth starting a new segment for.
+                let max_gap =
+                    this.settings.lock().unwrap().target_duration.max(1) as u64 * gst::SECOND_VAL;
+                let discontinuous_at = match info.data {
+                    Some(ref data) => detector.lock().unwrap().check(data, max_gap),
+                    None => None,
+                };
+                if let Some(running_time) = discontinuous_at {
+                    if let Some(element) = element_weak.upgrade() {
+                        this.on_discontinuity(&element, pad, running_time);
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            },
+        )
+        .unwrap();
+    }
+
+    /// Starts a new segment marked with `EXT-X-DISCONTINUITY` after a discontinuity of the input
+    /// at `running_time`.
+    fn on_discontinuity(&self, element: &super::FlexHlsSink, pad: &gst::Pad, running_time: u64) {
+        {
+            let mut state = self.state.lock().unwrap();
+            match &mut *state {
+                State::Stopped => return,
+                State::Started {
+                    discontinuity_pending,
+                    ..
+                } => {
+                    if discontinuity_pending.is_some() {
+                        return;
+                    }
+                    *discontinuity_pending = Some(running_time);
+                }
+            }
+        }
+
+        gst_info!(
+            CAT,
+            obj: element,
+            "Discontinuity on pad {}, starting a new segment",
+            pad.name()
+        );
+        self.split_now(element);
+    }
+
+    /// Closes the current segment at the next keyframe.
+    fn split_now(&self, element: &super::FlexHlsSink) {
+        {
+            let mut state = self.state.lock().unwrap();
+            match &mut *state {
+                State::Stopped => return,
+                State::Started {
+                    split_requested, ..
+                } => {
+                    // Requests made before the new segment starts end the same segment.
+                    if *split_requested {
+                        return;
+                    }
+                    *split_requested = true;
+                }
+            }
+        }
+
+        gst_debug!(CAT, obj: element, "Splitting at the next keyframe");
+        let splitmuxsink = self.settings.lock().unwrap().splitmuxsink.clone();
+        if let Some(splitmuxsink) = splitmuxsink {
+            if let Err(err) = splitmuxsink.emit_by_name("split-now", &[]) {
+                gst_warning!(CAT, obj: element, "Could not request a new segment: {}", err);
+            }
+        }
+    }
+
+    fn add_reference_timestamp_probe(&self, pad: &gst::GhostPad) {
+        let this = self.clone();
+        let reader = Mutex::new(ReferenceTimestampReader::default());
+        pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_pad, info| {
+                let timestamp = match info.data {
+                    Some(ref data) => reader.lock().unwrap().read(data),
+                    None => None,
+                };
+                if let Some(timestamp) = timestamp {
+                    if let State::Started {
+                        reference_timestamp,
+                        ..
+                    } = &mut *this.state.lock().unwrap()
+                    {
+                        *reference_timestamp = Some(timestamp);
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            },
+        )
+        .unwrap();
+    }
+
+    /// Keeps the metadata stream in step with the media stream of `pad`.
+    fn add_meta_probe(&self, element: &super::FlexHlsSink, pad: &gst::GhostPad) {
+        let this = self.clone();
+        let element_weak = element.downgrade();
+        let tracker = Mutex::new(RunningTimeTracker::default());
+        pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_pad, info| {
+                let element = match element_weak.upgrade() {
+                    Some(element) => element,
+                    None => return gst::PadProbeReturn::Ok,
+                };
+                match info.data {
+                    Some(gst::PadProbeData::Event(ref event))
+                        if event.type_() == gst::EventType::Eos =>
+                    {
+                        this.end_meta_stream(&element);
+                    }
+                    Some(ref data) => {
+                        if let Some(running_time) = tracker.lock().unwrap().running_time(data) {
+                            this.advance_meta_stream(&element, running_time);
+                        }
+                    }
+                    None => {}
+                }
+                gst::PadProbeReturn::Ok
+            },
+        )
+        .unwrap();
+    }
+
+    /// Watches `pad` for the split-now and SCTE-35 splice events.
+    fn add_custom_event_probe(&self, element: &super::FlexHlsSink, pad: &gst::GhostPad) {
+        let this = self.clone();
+        let element_weak = element.downgrade();
+        let tracker = Mutex::new((RunningTimeTracker::default(), None));
+        pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_pad, info| {
+                let mut tracker = tracker.lock().unwrap();
+                let (running_time_tracker, position) = &mut *tracker;
+                match info.data {
+                    Some(gst::PadProbeData::Event(ref event))
+                        if event.type_() == gst::EventType::CustomDownstream =>
+                    {
+                        let element = match element_weak.upgrade() {
+                            Some(element) => element,
+                            None => return gst::PadProbeReturn::Ok,
+                        };
+                        if is_split_now_event(event) {
+                            this.split_now(&element);
+                        } else if let Some(splice) = Splice::from_event(event, *position) {
+                            this.on_splice(&element, splice);
+                        }
+                    }
+                    Some(ref data) => {
+                        if let Some(running_time) = running_time_tracker.running_time(data) {
+                            *position = Some(running_time);
+                        }
+                    }
+                    None => {}
+                }
+                gst::PadProbeReturn::Ok
+            },
+        )
+        .unwrap();
+    }
+
+    /// Starts a new segment at the splice point, marked with `EXT-X-DATERANGE`.
+    fn on_splice(&self, element: &super::FlexHlsSink, splice: Splice) {
+        {
+            let mut state = self.state.lock().unwrap();
+            match &mut *state {
+                State::Stopped => return,
+                State::Started {
+                    pending_splices,
+                    current_segment_splice,
+                    ..
+                } => {
+                    // The same splice event is sent to each stream.
+                    if pending_splices
+                        .iter()
+                        .chain(current_segment_splice.iter())
+                        .any(|pending| pending.is_same_as(&splice))
+                    {
+                        return;
+                    }
+                    pending_splices.push(splice.clone());
+                }
+            }
+        }
+
+        gst_info!(
+            CAT,
+            obj: element,
+            "Splice {} at {}, starting a new segment",
+            splice.event_id,
+            splice.running_time
+        );
+        let splitmuxsink = self.settings.lock().unwrap().splitmuxsink.clone();
+        if let Some(splitmuxsink) = splitmuxsink {
+            // The splice point is then marked on the next segment started.
+            if let Err(err) = splitmuxsink.emit_by_name(
+                "split-at-running-time",
+                &[&gst::ClockTime::from_nseconds(splice.running_time)],
+            ) {
+                gst_warning!(
+                    CAT,
+                    obj: element,
+                    "Could not split at splice {}: {}",
+                    splice.event_id,
+                    err
+                );
+            }
+        }
+    }
+
+    /// Pad of the metadata stream, when it is fed by `insert-metadata`.
+    fn unlinked_meta_pad(&self) -> Option<gst::GhostPad> {
+        match &*self.meta.lock().unwrap() {
+            Some(meta) if !meta.pad.is_linked() => Some(meta.pad.clone()),
+            _ => None,
+        }
+    }
+
+    /// Sends the sticky events of the metadata stream, unless already sent.
+    fn start_meta_stream(&self, element: &super::FlexHlsSink, pad: &gst::GhostPad) {
+        if pad.sticky_event(gst::EventType::StreamStart, 0).is_some() {
+            return;
+        }
+
+        let stream_id = format!("{}/meta", element.name());
+        pad.send_event(gst::event::StreamStart::new(&stream_id));
+        pad.send_event(gst::event::Caps::new(
+            &gst::Caps::builder("meta/x-id3").build(),
+        ));
+        pad.send_event(gst::event::Segment::new(&gst::FormattedSegment::<
+            gst::ClockTime,
+        >::new()));
+    }
+
+    /// Fills the metadata stream with a gap up to `running_time`, as the muxer waits for data on
+    /// all its inputs before muxing.
+    fn advance_meta_stream(&self, element: &super::FlexHlsSink, running_time: u64) {
+        let pad = match self.unlinked_meta_pad() {
+            Some(pad) => pad,
+            None => return,
+        };
+        // Keeps the gaps in order with the tags inserted from other threads.
+        let _stream_lock = pad.stream_lock();
+
+        let position = match &mut *self.meta.lock().unwrap() {
+            Some(meta) => match meta.position {
+                Some(position) if running_time < position + META_GAP_INTERVAL => return,
+                position => {
+                    meta.position = Some(running_time);
+                    position.unwrap_or(running_time)
+                }
+            },
+            None => return,
+        };
+
+        self.start_meta_stream(element, &pad);
+        pad.send_event(gst::event::Gap::new(
+            gst::ClockTime::from_nseconds(position),
+            gst::ClockTime::from_nseconds(running_time - position),
+        ));
+    }
+
+    /// Ends the metadata stream along with the media streams.
+    fn end_meta_stream(&self, element: &super::FlexHlsSink) {
+        if let Some(pad) = self.unlinked_meta_pad() {
+            let _stream_lock = pad.stream_lock();
+            self.start_meta_stream(element, &pad);
+            pad.send_event(gst::event::Eos::new());
+        }
+    }
+
+    /// Muxes the tags as timed ID3 metadata at the current position of the media streams.
+    fn insert_metadata(&self, element: &super::FlexHlsSink, tags: &gst::TagList) -> bool {
+        let pad = match self.unlinked_meta_pad() {
+            Some(pad) => pad,
+            None => {
+                gst_warning!(
+                    CAT,
+                    obj: element,
+                    "Metadata can only be inserted through an unlinked meta pad"
+                );
+                return false;
+            }
+        };
+        let payload = match id3::tag_from_tags(tags) {
+            Some(payload) => payload,
+            None => {
+                gst_warning!(CAT, obj: element, "No tags to insert in {:?}", tags);
+                return false;
+            }
+        };
+
+        let _stream_lock = pad.stream_lock();
+        let position = match &*self.meta.lock().unwrap() {
+            Some(MetaStream {
+                position: Some(position),
+                ..
+            }) => *position,
+            _ => {
+                gst_warning!(CAT, obj: element, "Can not insert metadata before the media");
+                return false;
+            }
+        };
+
+        let mut buffer = gst::Buffer::from_mut_slice(payload);
+        buffer
+            .get_mut()
+            .unwrap()
+            .set_pts(gst::ClockTime::from_nseconds(position));
+        gst_debug!(CAT, obj: element, "Inserting metadata at {}", position);
+        pad.chain(buffer).is_ok()
+    }
 
-#[derive(Default, Clone)]
-pub struct FlexHlsSink {
-    settings: Arc<Mutex<Settings>>,
-    state: Arc<Mutex<State>>,
-}
+    /// Wall-clock time of the segment starting at `running_time`, formatted for
+    /// `EXT-X-PROGRAM-DATE-TIME`.
+    fn segment_date_time(
+        &self,
+        element: &super::FlexHlsSink,
+        mode: ProgramDateTime,
+        running_time: gst::ClockTime,
+        reference_timestamp: Option<ReferenceTimestamp>,
+    ) -> Option<String> {
+        let unix_time =
+            self.unix_time(element, mode, running_time.nseconds()?, reference_timestamp)?;
 
-impl FlexHlsSink {
-    fn new() -> Self {
-        Self {
-            settings: Arc::new(Mutex::new(Settings::default())),
-            state: Arc::new(Mutex::new(State::default())),
-        }
+        Some(
+            Utc.timestamp_nanos(unix_time)
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+        )
     }
 
-    fn start(
+    /// Wall-clock time of `running_time`, in nanoseconds since the UNIX epoch.
+    fn unix_time(
         &self,
         element: &super::FlexHlsSink,
-    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
-        gst_info!(CAT, obj: element, "Starting");
+        mode: ProgramDateTime,
+        running_time: u64,
+        reference_timestamp: Option<ReferenceTimestamp>,
+    ) -> Option<i64> {
+        let running_time = running_time as i64;
+        match (mode, reference_timestamp) {
+            (ProgramDateTime::None, _) => None,
+            (ProgramDateTime::ReferenceTimestamp, Some(reference)) => {
+                Some(reference.unix_time as i64 + running_time - reference.running_time as i64)
+            }
+            (ProgramDateTime::Clock, _) | (ProgramDateTime::ReferenceTimestamp, None) => {
+                // The running time is mapped to the time of the clock it was measured against.
+                let clock = element.clock()?;
+                let clock_time =
+                    (element.base_time().nseconds()? as i64).checked_add(running_time)?;
+                Some(self.clock_offset(&clock)?.checked_add(clock_time)?)
+            }
+        }
+    }
+
+    /// UNIX time at the time zero of `clock`, in nanoseconds. A realtime system clock runs on
+    /// the UNIX time, other clocks are compared to the system time once, so the running times of
+    /// all segments are mapped alike.
+    fn clock_offset(&self, clock: &gst::Clock) -> Option<i64> {
+        let mut calibration = self.clock_calibration.lock().unwrap();
+        if let Some(calibration) = &*calibration {
+            if calibration.clock == *clock {
+                return Some(calibration.offset);
+            }
+        }
+
+        let realtime = clock.is::<gst::SystemClock>()
+            && clock
+                .property("clock-type")
+                .ok()
+                .and_then(|clock_type| clock_type.get::<gst::ClockType>().ok())
+                == Some(gst::ClockType::Realtime);
+        let offset = match realtime {
+            true => 0,
+            false => {
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .ok()?
+                    .as_nanos() as i64;
+                now.checked_sub(clock.time().nseconds()? as i64)?
+            }
+        };
+
+        *calibration = Some(ClockCalibration {
+            clock: clock.clone(),
+            offset,
+        });
+        Some(offset)
+    }
 
-        let target_duration = {
+    /// Requests the split at the end of the interval of the epoch the segment opened at
+    /// `opened_at` is in.
+    fn schedule_aligned_split(&self, element: &super::FlexHlsSink, opened_at: u64) {
+        let (epoch_interval, wall_clock, splitmuxsink) = {
             let settings = self.settings.lock().unwrap();
-            settings.target_duration as f32
+            if !settings.align_to_epoch {
+                return;
+            }
+            (
+                settings.target_duration as u64 * gst::SECOND_VAL,
+                settings.wall_clock(),
+                settings.splitmuxsink.clone(),
+            )
         };
 
-        let mut state = self.state.lock().unwrap();
-        if let State::Stopped = *state {
-            *state = State::Started {
-                playlist: MediaPlaylist {
-                    version: GST_M3U8_PLAYLIST_VERSION,
-                    target_duration,
-                    media_sequence: 0,
-                    segments: vec![],
-                    discontinuity_sequence: 0,
-                    end_list: false,
-                    playlist_type: None,
-                    i_frames_only: false,
-                    start: None,
-                    independent_segments: false,
-                    unknown_tags: vec![],
-                },
-                playlist_render_state: PlaylistRenderState::Init,
-                playlist_index: 0,
-                current_segment_location: None,
-                fragment_opened_at: None,
-                old_segment_locations: vec![],
+        let split_at = {
+            let mut state = self.state.lock().unwrap();
+            let (reference_timestamp, next_aligned_split) = match &mut *state {
+                State::Stopped => return,
+                State::Started {
+                    reference_timestamp,
+                    next_aligned_split,
+                    ..
+                } => (*reference_timestamp, next_aligned_split),
             };
-        }
 
-        Ok(gst::StateChangeSuccess::Success)
+            let unix_time =
+                match self.unix_time(element, wall_clock, opened_at, reference_timestamp) {
+                    Some(unix_time) if epoch_interval > 0 => unix_time as u64,
+                    _ => return,
+                };
+            let split_at = opened_at + epoch_interval - unix_time % epoch_interval;
+            // Segments started on demand end at the split already requested for the interval.
+            if next_aligned_split.map_or(false, |next| next >= split_at) {
+                return;
+            }
+            *next_aligned_split = Some(split_at);
+            split_at
+        };
+
+        gst_debug!(CAT, obj: element, "Next aligned split at {}", split_at);
+        if let Some(splitmuxsink) = splitmuxsink {
+            // The segment then ends at the target duration, past the boundary of the interval.
+            if let Err(err) = splitmuxsink.emit_by_name(
+                "split-at-running-time",
+                &[&gst::ClockTime::from_nseconds(split_at)],
+            ) {
+                gst_warning!(
+                    CAT,
+                    obj: element,
+                    "Could not split at {}: {}",
+                    split_at,
+                    err
+                );
+            }
+        }
     }
 
-    fn on_format_location(
+    fn write_init_segment(
         &self,
         element: &super::FlexHlsSink,
-        fragment_id: u32,
-    ) -> Result<String, String> {
-        gst_info!(
-            CAT,
-            "Starting the formatting of the fragment-id: {}",
-            fragment_id
-        );
+        init_location: &str,
+        header: &[u8],
+    ) -> Result<(), String> {
+        let mut init_stream = self
+            .open_segment_stream(element, init_location, false)?
+            .into_write();
 
-        let mut state = self.state.lock().unwrap();
-        let current_segment_location = match &mut *state {
-            State::Stopped => return Err("Not in Started state".to_string()),
-            State::Started {
-                current_segment_location,
-                ..
-            } => current_segment_location,
-        };
+        init_stream
+            .write_all(header)
+            .map_err(|err| err.to_string())?;
+        init_stream.flush().map_err(|err| err.to_string())
+    }
 
-        let settings = self.settings.lock().unwrap();
+    pub fn set_storage(&self, storage: Arc<dyn HlsStorage>) {
+        self.settings.lock().unwrap().storage = Some(storage);
+    }
 
-        let seq_num = format!("{:0>5}", fragment_id);
-        let segment_file_location = settings
-            .location
-            .replace(BACKWARDS_COMPATIBLE_PLACEHOLDER, &seq_num);
-        gst_trace!(CAT, "Segment location formatted: {}", segment_file_location);
+    fn storage(&self) -> Option<Arc<dyn HlsStorage>> {
+        self.settings.lock().unwrap().storage()
+    }
 
-        *current_segment_location = Some(segment_file_location.clone());
+    fn write_queue(&self) -> Option<Arc<WriteQueue>> {
+        self.settings.lock().unwrap().write_queue.clone()
+    }
 
-        let fragment_stream = element
-            .emit_by_name(SIGNAL_GET_FRAGMENT_STREAM, &[&segment_file_location])
-            .expect("Error while getting fragment stream")
-            .unwrap()
+    fn open_error(element: &super::FlexHlsSink, location: &str, err: std::io::Error) -> String {
+        let error_msg = gst::error_msg!(
+            gst::ResourceError::OpenWrite,
+            [
+                "Could not open file {} for writing: {}",
+                location,
+                err.to_string(),
+            ]
+        );
+        element.post_error_message(error_msg);
+        err.to_string()
+    }
+
+    /// Opens the stream a segment is written to, from the writer thread, the storage or
+    /// `get-fragment-stream`. Only the data of `droppable` segments is dropped when the write
+    /// queue is full.
+    fn open_segment_stream(
+        &self,
+        element: &super::FlexHlsSink,
+        location: &str,
+        droppable: bool,
+    ) -> Result<gio::OutputStream, String> {
+        if let Some(write_queue) = self.write_queue() {
+            return write_queue
+                .open_segment(location, droppable)
+                .map_err(|err| Self::open_error(element, location, err));
+        }
+        if let Some(storage) = self.storage() {
+            return storage
+                .open_segment(location)
+                .map_err(|err| Self::open_error(element, location, err));
+        }
+
+        element
+            .emit_by_name(SIGNAL_GET_FRAGMENT_STREAM, &[&location])
+            .map_err(|err| err.to_string())?
+            .ok_or_else(|| format!("No stream to write {}", location))?
             .get::<gio::OutputStream>()
-            .map_err(|err| err.to_string())?;
+            .map_err(|err| err.to_string())
+    }
 
-        let giostreamsink = settings.giostreamsink.as_ref().unwrap();
-        giostreamsink
-            .set_property("stream", &fragment_stream)
-            .unwrap();
+    /// Opens the stream a playlist is written to, from the writer thread, the storage or
+    /// `get-playlist-stream`.
+    fn open_playlist_stream(
+        &self,
+        element: &super::FlexHlsSink,
+        location: &str,
+    ) -> std::io::Result<gio::OutputStream> {
+        if let Some(write_queue) = self.write_queue() {
+            return write_queue.open_playlist(location);
+        }
+        if let Some(storage) = self.storage() {
+            return storage.open_playlist(location);
+        }
 
-        gst_info!(
-            CAT,
-            "New segment location: {}",
-            current_segment_location.as_ref().unwrap()
-        );
-        Ok(segment_file_location)
+        let other = |err: String| std::io::Error::new(std::io::ErrorKind::Other, err);
+        element
+            .emit_by_name(SIGNAL_GET_PLAYLIST_STREAM, &[&location])
+            .map_err(|err| other(err.to_string()))?
+            .ok_or_else(|| other(format!("No stream to write {}", location)))?
+            .get::<gio::OutputStream>()
+            .map_err(|err| other(err.to_string()))
     }
 
-    fn new_file_stream<P>(
+    /// Makes the playlist written to its stream visible, through the storage or
+    /// `commit-playlist-stream`.
+    fn commit_playlist_stream(
         &self,
         element: &super::FlexHlsSink,
-        location: &P,
-    ) -> Result<gio::OutputStream, String>
-    where
-        P: AsRef<path::Path>,
-    {
-        let element_weak = element.downgrade();
-        let file = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(location)
-            .map_err(move |err| {
-                let error_msg = gst::error_msg!(
-                    gst::ResourceError::OpenWrite,
-                    [
-                        "Could not open file {} for writing: {}",
-                        location.as_ref().to_str().unwrap(),
-                        err.to_string(),
-                    ]
+        location: &str,
+    ) -> std::io::Result<()> {
+        if let Some(write_queue) = self.write_queue() {
+            return write_queue.commit_playlist(location);
+        }
+        match self.storage() {
+            Some(storage) => storage.commit_playlist(location),
+            None => SignalStorage(element.downgrade()).commit_playlist(location),
+        }
+    }
+
+    /// Deletes an old segment, through the storage or `delete-fragment`.
+    fn delete_segment(&self, element: &super::FlexHlsSink, location: &str) {
+        if let Some(write_queue) = self.write_queue() {
+            if let Err(err) = write_queue.delete(location) {
+                gst_warning!(CAT, obj: element, "Could not delete {}: {}", location, err);
+            }
+            return;
+        }
+        match self.storage() {
+            Some(storage) => {
+                if let Err(err) = storage.delete(location) {
+                    gst_warning!(CAT, obj: element, "Could not delete {}: {}", location, err);
+                }
+            }
+            None => {
+                let _ = element
+                    .emit_by_name(SIGNAL_DELETE_FRAGMENT, &[&location])
+                    .expect("Error while processing signal handler");
+            }
+        }
+    }
+
+    fn new_file_stream(
+        &self,
+        element: &super::FlexHlsSink,
+        location: &str,
+    ) -> Result<gio::OutputStream, String> {
+        FsStorage
+            .open_segment(location)
+            .map_err(|err| Self::open_error(element, location, err))
+    }
+
+    fn new_playlist_stream(
+        &self,
+        element: &super::FlexHlsSink,
+        location: &str,
+    ) -> Result<gio::OutputStream, String> {
+        FsStorage.open_playlist(location).map_err(|err| {
+            gst_warning!(
+                CAT,
+                obj: element,
+                "Could not open file {} for writing: {}",
+                location,
+                err
+            );
+            err.to_string()
+        })
+    }
+
+    /// Replaces the playlist with its temporary file, returns whether it succeeded. The
+    /// failure is counted along with the other playlist write failures.
+    fn commit_playlist(&self, element: &super::FlexHlsSink, location: &str) -> bool {
+        match FsStorage.commit_playlist(location) {
+            Ok(_) => {
+                gst_trace!(CAT, obj: element, "Replaced playlist {}", location);
+                true
+            }
+            // A custom stream provider did not write to the temporary file.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => true,
+            Err(err) => {
+                gst_warning!(
+                    CAT,
+                    obj: element,
+                    "Could not replace playlist {}: {}",
+                    location,
+                    err
                 );
-                let element = element_weak.upgrade().unwrap();
-                element.post_error_message(error_msg);
-                err.to_string()
-            })?;
-        Ok(gio::WriteOutputStream::new(file).upcast())
+                false
+            }
+        }
     }
 
-    fn delete_fragment<P>(&self, location: &P)
-    where
-        P: AsRef<path::Path>,
-    {
-        let _ = fs::remove_file(location).map_err(|err| {
+    fn delete_fragment(&self, location: &str) {
+        let _ = FsStorage.delete(location).map_err(|err| {
             gst_warning!(CAT, "Could not delete segment file: {}", err.to_string());
         });
     }
 
+    /// Adds the segment closed at `fragment_closed_at`, if any, and writes the playlist, then
+    /// posts the `hls-segment-added`, `hls-segment-removed` and `hls-playlist-updated` element
+    /// messages, or the error preventing it.
     fn write_playlist(
         &self,
         element: &super::FlexHlsSink,
         fragment_closed_at: Option<gst::ClockTime>,
     ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        let mut messages = vec![];
+        let res = self.update_playlist(element, fragment_closed_at, &mut messages);
+
+        // Posted once the state is unlocked, so synchronous bus handlers can use the element.
+        for message in messages {
+            let _ = element.post_message(message);
+        }
+        res.map_err(|error_msg| {
+            element.post_error_message(error_msg);
+            gst::StateChangeError
+        })
+    }
+
+    fn update_playlist(
+        &self,
+        element: &super::FlexHlsSink,
+        fragment_closed_at: Option<gst::ClockTime>,
+        messages: &mut Vec<gst::Message>,
+    ) -> Result<gst::StateChangeSuccess, gst::ErrorMessage> {
         gst_info!(CAT, obj: element, "Preparing to write new playlist");
 
         let mut state = self.state.lock().unwrap();
-        match &mut *state {
-            State::Stopped => return Err(gst::StateChangeError),
+        let (playlist_files, deleted_segments) = match &mut *state {
+            State::Stopped => {
+                return Err(gst::error_msg!(
+                    gst::CoreError::StateChange,
+                    ["Can not write the playlist of a stopped sink"]
+                ))
+            }
             State::Started {
                 fragment_opened_at,
                 playlist,
                 current_segment_location,
                 playlist_render_state,
                 playlist_index,
+                current_segment_sequence,
+                unlisted_segments,
                 old_segment_locations,
+                init_segment,
+                part_tracker,
+                encryption_key,
+                current_segment_key,
+                segment_key_locations,
+                current_segment_discontinuity,
+                current_segment_date_time,
+                output_bytes,
+                segment_offset,
+                single_file_stream,
+                keyframe_tracker,
+                current_segment_splice,
+                splice_start_dates,
+                discontinuity_pending,
                 ..
             } => {
                 gst_info!(CAT, "COUNT {}", playlist.segments.len());
 
                 // Only add fragment if it's complete.
                 if let Some(fragment_closed_at) = fragment_closed_at {
-                    let segment_location = current_segment_location
-                        .take()
-                        .ok_or(gst::StateChangeError)?;
+                    // Only taken once the segment is accepted, so failures leave the state as is.
+                    let segment_location = current_segment_location.clone().ok_or_else(|| {
+                        gst::error_msg!(gst::CoreError::Failed, ["No segment was opened"])
+                    })?;
+                    let (
+                        segment_uri,
+                        cue_tags,
+                        target_duration,
+                        target_duration_policy,
+                        vod,
+                        align_to_epoch,
+                    ) = {
+                        let settings = self.settings.lock().unwrap();
+                        (
+                            settings.uri_for(&segment_location),
+                            settings.cue_tags,
+                            settings.target_duration,
+                            settings.target_duration_policy,
+                            settings.playlist_type == PlaylistType::Vod,
+                            settings.align_to_epoch,
+                        )
+                    };
+
+                    // Segments appended to a single file are addressed by their byte range.
+                    let byte_range = single_file_stream.as_ref().map(|_| ByteRange {
+                        offset: *segment_offset,
+                        length: *output_bytes - *segment_offset,
+                    });
+
+                    let no_duration = || {
+                        gst::error_msg!(
+                            gst::StreamError::Failed,
+                            ["Segment {} has no duration", segment_location]
+                        )
+                    };
+                    let fragment_opened_at = fragment_opened_at.ok_or_else(no_duration)?;
+                    let segment_duration = fragment_closed_at - fragment_opened_at;
+                    let duration =
+                        segment_duration.mseconds().ok_or_else(no_duration)? as f32 / 1_000f32;
+
+                    // The duration of each segment, rounded to the nearest integer, must not
+                    // exceed EXT-X-TARGETDURATION. Without a target duration, segments are only
+                    // split at keyframes and none is enforced.
+                    let rounded_duration = duration.round();
+                    let exceeds_target_duration =
+                        target_duration > 0 && rounded_duration > playlist.target_duration;
+                    if exceeds_target_duration {
+                        let text = format!(
+                            "Segment {} lasts {:.3}s, longer than the target duration of {}s",
+                            segment_location, duration, playlist.target_duration
+                        );
+                        gst_warning!(CAT, obj: element, "{}", text);
+                        messages.push(
+                            gst::message::Warning::builder(gst::StreamError::Format, &text)
+                                .src(element)
+                                .build(),
+                        );
+
+                        // The target duration can not change once the playlist is published,
+                        // including before a restart. A VOD playlist is only published at the
+                        // end.
+                        let published = (*playlist_render_state == PlaylistRenderState::Started
+                            || !playlist.segments.is_empty())
+                            && !vod;
+                        match target_duration_policy {
+                            TargetDurationPolicy::Bump if !published => {
+                                gst_info!(
+                                    CAT,
+                                    obj: element,
+                                    "Raising the target duration to {}s",
+                                    rounded_duration
+                                );
+                                playlist.target_duration = rounded_duration;
+                            }
+                            // Rather than publishing a playlist it does not fit in, the segment is
+                            // left out and the playlist is not updated.
+                            _ => {
+                                gst_warning!(
+                                    CAT,
+                                    obj: element,
+                                    "Leaving segment {} out of the playlist",
+                                    segment_location
+                                );
+                                *current_segment_location = None;
+                                current_segment_key.take();
+                                segment_key_locations.remove(&segment_location);
+                                let _ = playlist.take_pending_parts();
+                                playlist.set_preload_hint(None);
+                                // The next segment listed does not follow the previous one.
+                                let closed_at = fragment_closed_at.nseconds().unwrap_or(0);
+                                *discontinuity_pending = Some(
+                                    discontinuity_pending.map_or(closed_at, |at| at.min(closed_at)),
+                                );
+                                // Segments aligned to the epoch keep the number of their interval.
+                                if !align_to_epoch {
+                                    *unlisted_segments += 1;
+                                }
+                                self.stats.lock().unwrap().target_duration_violations += 1;
+                                // The single output file keeps the data of the segment.
+                                let single_file = single_file_stream.is_some();
+                                drop(state);
+
+                                if !single_file {
+                                    self.delete_segment(element, &segment_location);
+                                }
+                                return Ok(gst::StateChangeSuccess::Success);
+                            }
+                        }
+                    }
+                    *current_segment_location = None;
 
-                    playlist.segments.push(MediaSegment {
-                        uri: segment_location.clone(),
-                        duration: {
-                            let fragment_opened_at =
-                                fragment_opened_at.as_ref().ok_or(gst::StateChangeError)?;
+                    let mut parts = playlist.take_pending_parts();
+                    let parts_duration = parts.iter().map(|part| part.duration).sum::<f32>();
+                    if let Some(last_part) =
+                        part_tracker.finish(&segment_uri, duration - parts_duration)
+                    {
+                        parts.push(last_part);
+                    }
+
+                    let splice = current_segment_splice.take();
+                    let program_date_time = current_segment_date_time.take();
+                    let daterange = splice.as_ref().zip(program_date_time.as_ref()).map(
+                        |(splice, start_date)| {
+                            let out_start_date = match splice.out_of_network {
+                                true => {
+                                    splice_start_dates.insert(splice.event_id, start_date.clone());
+                                    None
+                                }
+                                false => splice_start_dates.remove(&splice.event_id),
+                            };
+                            splice.daterange(start_date, out_start_date.as_deref())
+                        },
+                    );
 
-                            let segment_duration = fragment_closed_at - fragment_opened_at;
+                    let iframes = keyframe_tracker.finish(duration);
+                    let keyframe = iframes
+                        .first()
+                        .map_or(false, |iframe| iframe.offset == *segment_offset);
+                    let keyframe_size = iframes.iter().map(|iframe| iframe.length).sum::<u64>();
+                    let sequence = current_segment_sequence.saturating_sub(*unlisted_segments);
 
-                            segment_duration.mseconds().ok_or(gst::StateChangeError)? as f32
-                                / 1_000f32
+                    playlist.push_segment(
+                        MediaSegment {
+                            uri: segment_uri.clone(),
+                            duration,
+                            title: None,
+                            byte_range: None,
+                            discontinuity: std::mem::take(current_segment_discontinuity),
+                            key: current_segment_key.take(),
+                            map: match init_segment {
+                                InitSegment::Written(uri) => Some(Map {
+                                    uri: uri.clone(),
+                                    byte_range: None,
+                                }),
+                                InitSegment::Pending(_) => None,
+                            },
+                            program_date_time,
+                            daterange,
                         },
-                        title: None,
-                        byte_range: None,
-                        discontinuity: false,
-                        key: None,
-                        map: None,
-                        program_date_time: None,
-                        daterange: None,
+                        sequence,
+                        parts,
+                        iframes,
+                        splice.filter(|_| cue_tags).map(|splice| splice.cue_tag()),
+                    );
+                    if let Some(byte_range) = byte_range {
+                        playlist.set_last_segment_byte_range(byte_range);
+                    }
+                    playlist.set_preload_hint(None);
+
+                    let dropped = self.write_queue().map_or(false, |write_queue| {
+                        write_queue.take_dropped(&segment_location)
                     });
+                    if dropped {
+                        gst_warning!(
+                            CAT,
+                            obj: element,
+                            "Segment {} dropped, the write queue being full",
+                            segment_location
+                        );
+                        playlist.mark_last_segment_gap();
+                        segment_key_locations.remove(&segment_location);
+                    }
+
+                    let size = *output_bytes - *segment_offset;
+                    self.stats.lock().unwrap().add_segment(
+                        size,
+                        segment_duration,
+                        exceeds_target_duration,
+                    );
 
-                    old_segment_locations.push(segment_location);
+                    let structure = gst::Structure::builder("hls-segment-added")
+                        .field("uri", &segment_uri)
+                        .field("location", &segment_location)
+                        .field("sequence", &sequence)
+                        .field("duration", &segment_duration)
+                        .field("size", &size)
+                        .field("keyframe-size", &keyframe_size)
+                        .field("running-time", &fragment_opened_at)
+                        .field("keyframe", &keyframe)
+                        .field("gap", &dropped)
+                        .build();
+                    messages.push(
+                        gst::message::Element::builder(structure)
+                            .src(element)
+                            .build(),
+                    );
+
+                    // The single output file is never deleted, dropped segments already are.
+                    if !dropped && single_file_stream.is_none() {
+                        old_segment_locations.push(segment_location);
+                    }
+                    *playlist_index = (*playlist_index).max(*current_segment_sequence as usize + 1);
                 }
 
-                let (playlist_location, max_num_segments, max_playlist_length) = {
+                let (playlist_location, iframe_playlist_location, max_num_segments, playlist_type) = {
                     let settings = self.settings.lock().unwrap();
+                    settings.trim_playlist(playlist);
                     (
                         settings.playlist_location.clone(),
+                        settings.iframe_playlist_location.clone(),
                         settings.max_num_segment_files,
-                        settings.playlist_length as usize,
+                        settings.playlist_type,
                     )
                 };
 
-                // TODO: remove old segments from playlist
-                if playlist.segments.len() > max_playlist_length {
-                    for _ in 0..playlist.segments.len() - max_playlist_length {
-                        let _ = playlist.segments.remove(0);
+                // A VOD playlist is only written once, when complete.
+                if playlist_type == PlaylistType::Vod && !playlist.end_list {
+                    if fragment_closed_at.is_some() {
+                        *playlist_render_state = PlaylistRenderState::Started;
                     }
+                    gst_debug!(CAT, obj: element, "Postponing VOD playlist until EOS");
+                    return Ok(gst::StateChangeSuccess::Success);
                 }
 
-                *playlist_index += 1;
-                playlist.media_sequence = *playlist_index as i32 - playlist.segments.len() as i32;
-
-                let mut playlist_stream = element
-                    .emit_by_name(SIGNAL_GET_PLAYLIST_STREAM, &[&playlist_location])
-                    .expect("Error while getting playlist stream")
-                    .ok_or(gst::StateChangeError)?
-                    .get::<gio::OutputStream>()
-                    .map_err(|err| {
-                        gst_error!(
-                            CAT,
-                            "Could not get stream to write playlist content: {}",
-                            err.to_string()
-                        );
-                        gst::StateChangeError
-                    })?
-                    .into_write();
-
-                playlist.write_to(&mut playlist_stream).map_err(|err| {
-                    gst_error!(CAT, "Could not write new playlist: {}", err.to_string());
-                    gst::StateChangeError
+                let mut playlist_content = Vec::new();
+                playlist.write_to(&mut playlist_content).map_err(|err| {
+                    gst::error_msg!(
+                        gst::LibraryError::Failed,
+                        ["Could not render new playlist: {}", err.to_string()]
+                    )
                 })?;
-                let _ = playlist_stream.flush().unwrap();
+                let mut playlist_files = vec![(playlist_location, playlist_content)];
+
+                // The I-frame playlist only changes when segments are added or at the end.
+                if let (Some(iframe_playlist_location), true) = (
+                    iframe_playlist_location,
+                    fragment_closed_at.is_some() || playlist.end_list,
+                ) {
+                    let mut iframe_playlist_content = Vec::new();
+                    playlist
+                        .write_iframes_to(&mut iframe_playlist_content)
+                        .map_err(|err| {
+                            gst::error_msg!(
+                                gst::LibraryError::Failed,
+                                ["Could not render new I-frame playlist: {}", err.to_string()]
+                            )
+                        })?;
+                    playlist_files.push((iframe_playlist_location, iframe_playlist_content));
+                }
 
                 *playlist_render_state = PlaylistRenderState::Started;
 
-                if old_segment_locations.len() > max_num_segments {
+                // Old segments, along with the keys none of the remaining segments use.
+                let mut deleted_segments = vec![];
+                if playlist_type.is_sliding() && old_segment_locations.len() > max_num_segments {
                     for _ in 0..old_segment_locations.len() - max_num_segments {
                         let old_segment_location = old_segment_locations.remove(0);
-                        let _ = element
-                            .emit_by_name(SIGNAL_DELETE_FRAGMENT, &[&old_segment_location])
-                            .expect("Error while processing signal handler");
+                        let key_location = segment_key_locations
+                            .remove(&old_segment_location)
+                            .filter(|key_location| {
+                                let in_use = encryption_key
+                                    .as_ref()
+                                    .map_or(false, |key| key.location == *key_location)
+                                    || segment_key_locations
+                                        .values()
+                                        .any(|location| location == key_location);
+                                !in_use
+                            });
+                        deleted_segments.push((old_segment_location, key_location));
                     }
                 }
+
+                (playlist_files, deleted_segments)
             }
         };
+        drop(state);
+
+        // The stream signals are emitted once the state is unlocked, so their handlers can read
+        // the properties of the element.
+        for (location, content) in playlist_files {
+            if self.write_playlist_file(element, &location, &content, messages)? {
+                messages.push(playlist_updated_message(element, &location, &content));
+            }
+        }
+
+        for (old_segment_location, key_location) in deleted_segments {
+            self.delete_segment(element, &old_segment_location);
+            self.stats.lock().unwrap().segments_deleted += 1;
+
+            if let Some(key_location) = key_location {
+                gst_debug!(CAT, obj: element, "Deleting key {}", key_location);
+                self.delete_segment(element, &key_location);
+            }
+
+            let uri = self.settings.lock().unwrap().uri_for(&old_segment_location);
+            let structure = gst::Structure::builder("hls-segment-removed")
+                .field("uri", &uri)
+                .field("location", &old_segment_location)
+                .build();
+            messages.push(
+                gst::message::Element::builder(structure)
+                    .src(element)
+                    .build(),
+            );
+        }
 
         gst_debug!(CAT, obj: element, "Wrote new playlist file!");
         Ok(gst::StateChangeSuccess::Success)
     }
 
+    /// Writes a complete playlist to the stream provided by `get-playlist-stream`, then commits it.
+    ///
+    /// Failing to write a playlist is not fatal, the next version replaces it: the failure is
+    /// counted and pushed to `messages` as a warning, and `false` returned. Only a full write
+    /// queue, with the `error` policy, fails.
+    fn write_playlist_file(
+        &self,
+        element: &super::FlexHlsSink,
+        location: &str,
+        content: &[u8],
+        messages: &mut Vec<gst::Message>,
+    ) -> Result<bool, gst::ErrorMessage> {
+        let res = self
+            .open_playlist_stream(element, location)
+            .map_err(|err| {
+                (
+                    err.kind(),
+                    format!("Could not open file {} for writing: {}", location, err),
+                )
+            })
+            .and_then(|playlist_stream| {
+                playlist_stream
+                    .write_all(content, gio::NONE_CANCELLABLE)
+                    .and_then(|_| playlist_stream.close(gio::NONE_CANCELLABLE))
+                    .map(|_| ())
+                    .map_err(|err| {
+                        let kind = match err.kind::<gio::IOErrorEnum>() {
+                            Some(gio::IOErrorEnum::WouldBlock) => std::io::ErrorKind::WouldBlock,
+                            _ => std::io::ErrorKind::Other,
+                        };
+                        (
+                            kind,
+                            format!("Could not write new playlist {}: {}", location, err),
+                        )
+                    })
+            })
+            // The playlist is complete, let the stream provider make it visible.
+            .and_then(|_| {
+                self.commit_playlist_stream(element, location)
+                    .map_err(|err| {
+                        (
+                            err.kind(),
+                            format!("Could not replace playlist {}: {}", location, err),
+                        )
+                    })
+            });
+
+        let (kind, text) = match res {
+            Ok(()) => {
+                self.stats.lock().unwrap().playlists_written += 1;
+                return Ok(true);
+            }
+            Err(err) => err,
+        };
+        self.stats.lock().unwrap().playlist_write_failures += 1;
+        if kind == std::io::ErrorKind::WouldBlock {
+            return Err(gst::error_msg!(gst::ResourceError::Write, ["{}", text]));
+        }
+
+        gst_warning!(CAT, obj: element, "{}", text);
+        messages.push(
+            gst::message::Warning::builder(gst::ResourceError::Write, &text)
+                .src(element)
+                .build(),
+        );
+        Ok(false)
+    }
+
+    fn subtitle_chain(
+        &self,
+        pad: &gst::Pad,
+        buffer: gst::Buffer,
+    ) -> Result<gst::FlowSuccess, gst::FlowError> {
+        let mut subtitles = self.subtitles.lock().unwrap();
+        let track = subtitles
+            .iter_mut()
+            .find(|track| &track.pad == pad)
+            .ok_or(gst::FlowError::NotLinked)?;
+
+        let start = match track.segment.as_ref() {
+            Some(segment) => segment.to_running_time(buffer.pts()).nseconds(),
+            None => return Err(gst::FlowError::NotNegotiated),
+        };
+        let start = match start {
+            Some(start) => start,
+            // Outside of the segment, or without timestamp.
+            None => return Ok(gst::FlowSuccess::Ok),
+        };
+        let end = start + buffer.duration().nseconds().unwrap_or(DEFAULT_CUE_DURATION);
+
+        let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
+        if let Some(cue) = Cue::from_payload(start, end, &map) {
+            gst_trace!(CAT, obj: pad, "New cue {:?}", cue);
+            track.cues.push(cue);
+        }
+
+        Ok(gst::FlowSuccess::Ok)
+    }
+
+    fn subtitle_event(&self, pad: &gst::Pad, event: gst::Event) -> bool {
+        if let gst::EventView::Segment(segment) = event.view() {
+            let mut subtitles = self.subtitles.lock().unwrap();
+            if let Some(track) = subtitles.iter_mut().find(|track| &track.pad == pad) {
+                track.segment = segment.segment().downcast_ref::<gst::ClockTime>().cloned();
+            }
+        }
+
+        // Nothing is downstream of the subtitle pads.
+        true
+    }
+
+    /// Writes the subtitle segments matching the video segment between `segment_times`, or the
+    /// final subtitle playlists when `None`.
+    fn write_subtitles(&self, element: &super::FlexHlsSink, segment_times: Option<(u64, u64)>) {
+        let outputs = {
+            let settings = self.settings.lock().unwrap();
+            let mut subtitles = self.subtitles.lock().unwrap();
+            subtitles
+                .iter_mut()
+                .map(|track| {
+                    let segment = match segment_times {
+                        Some((start, end)) => Some(track.complete_segment(&settings, start, end)),
+                        None => {
+                            track.playlist.end_list = true;
+                            None
+                        }
+                    };
+                    SubtitleOutput {
+                        segment,
+                        playlist: track
+                            .render_playlist(&settings)
+                            .map(|content| (track.playlist_location.clone(), content)),
+                        deleted_segment_locations: track.take_deleted_segment_locations(&settings),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for output in outputs {
+            if let Some((location, content)) = output.segment {
+                if let Err(err) = self.write_subtitle_segment(element, &location, &content) {
+                    gst_error!(
+                        CAT,
+                        obj: element,
+                        "Could not write subtitle segment {}: {}",
+                        location,
+                        err
+                    );
+                }
+            }
+            if let Some((location, content)) = output.playlist {
+                let mut messages = vec![];
+                let res = self.write_playlist_file(element, &location, &content, &mut messages);
+                for message in messages {
+                    let _ = element.post_message(message);
+                }
+                if let Err(error_msg) = res {
+                    element.post_error_message(error_msg);
+                }
+            }
+            for location in output.deleted_segment_locations {
+                self.delete_segment(element, &location);
+            }
+        }
+    }
+
+    fn write_subtitle_segment(
+        &self,
+        element: &super::FlexHlsSink,
+        location: &str,
+        content: &[u8],
+    ) -> Result<(), String> {
+        let stream = self.open_segment_stream(element, location, false)?;
+
+        stream
+            .write_all(content, gio::NONE_CANCELLABLE)
+            .and_then(|_| stream.close(gio::NONE_CANCELLABLE))
+            .map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
     fn write_final_playlist(
         &self,
         element: &super::FlexHlsSink,
@@ -345,9 +3114,32 @@ impl FlexHlsSink {
         gst_debug!(CAT, obj: element, "Stopping");
 
         let mut state = self.state.lock().unwrap();
-        if let State::Started { .. } = *state {
+        if let State::Started {
+            single_file_stream, ..
+        } = &mut *state
+        {
+            if let Some((location, stream)) = single_file_stream.take() {
+                if let Err(err) = stream.close(gio::NONE_CANCELLABLE) {
+                    gst_error!(CAT, obj: element, "Could not close {}: {}", location, err);
+                }
+            }
             *state = State::Stopped;
         }
+        drop(state);
+
+        // The writes are done first, as they may be uploads.
+        let (write_queue, upload_storage) = {
+            let mut settings = self.settings.lock().unwrap();
+            (settings.write_queue.take(), settings.upload_storage.take())
+        };
+        if write_queue.is_some() {
+            gst_debug!(CAT, obj: element, "Waiting for the pending writes");
+            drop(write_queue);
+        }
+        if upload_storage.is_some() {
+            gst_debug!(CAT, obj: element, "Waiting for the pending uploads");
+            drop(upload_storage);
+        }
 
         gst_debug!(CAT, obj: element, "Stopped");
     }
@@ -390,17 +3182,105 @@ impl BinImpl for FlexHlsSink {
                                 match &mut *state {
                                     State::Stopped => return,
                                     State::Started {
-                                        fragment_opened_at, ..
-                                    } => *fragment_opened_at = Some(new_fragment_opened_at),
+                                        fragment_opened_at,
+                                        playlist,
+                                        part_tracker,
+                                        current_segment_location,
+                                        reference_timestamp,
+                                        current_segment_date_time,
+                                        output_bytes,
+                                        segment_offset,
+                                        keyframe_tracker,
+                                        pending_timestamp_tag,
+                                        pending_splices,
+                                        current_segment_splice,
+                                        split_requested,
+                                        ..
+                                    } => {
+                                        let settings = self.settings.lock().unwrap();
+                                        if !settings.single_file {
+                                            *output_bytes = 0;
+                                        }
+                                        if settings.segment_format == SegmentFormat::PackedAudio {
+                                            *pending_timestamp_tag =
+                                                new_fragment_opened_at.nseconds().map(|time| {
+                                                    id3::transport_stream_timestamp_tag(
+                                                        time * 90_000 / gst::SECOND_VAL
+                                                            + MPEGTS_CLOCK_BASE,
+                                                    )
+                                                });
+                                        }
+                                        *segment_offset = *output_bytes;
+                                        *split_requested = false;
+                                        *fragment_opened_at = Some(new_fragment_opened_at);
+                                        *part_tracker = PartTracker::starting_at(*segment_offset);
+                                        *keyframe_tracker = KeyframeTracker::default();
+                                        *current_segment_date_time = self.segment_date_time(
+                                            element,
+                                            settings.program_date_time,
+                                            new_fragment_opened_at,
+                                            *reference_timestamp,
+                                        );
+                                        // The segment starts at the splice points it was split
+                                        // for.
+                                        let opened_at =
+                                            new_fragment_opened_at.nseconds().unwrap_or(0);
+                                        let (started, pending): (Vec<_>, Vec<_>) = pending_splices
+                                            .drain(..)
+                                            .partition(|splice| splice.running_time <= opened_at);
+                                        *pending_splices = pending;
+                                        if let Some(splice) = started.into_iter().last() {
+                                            // `EXT-X-DATERANGE` requires the segment to have a
+                                            // program date time.
+                                            if current_segment_date_time.is_none() {
+                                                *current_segment_date_time = self
+                                                    .segment_date_time(
+                                                        element,
+                                                        ProgramDateTime::Clock,
+                                                        new_fragment_opened_at,
+                                                        *reference_timestamp,
+                                                    );
+                                            }
+                                            *current_segment_splice = Some(splice);
+                                        }
+                                        playlist.set_preload_hint(
+                                            current_segment_location.as_ref().map(|location| {
+                                                PreloadHint {
+                                                    uri: settings.uri_for(location),
+                                                    offset: *segment_offset,
+                                                }
+                                            }),
+                                        );
+                                    }
                                 };
+                                drop(state);
+
+                                if let Some(opened_at) = new_fragment_opened_at.nseconds() {
+                                    self.schedule_aligned_split(element, opened_at);
+                                }
                             }
                         }
                         "splitmuxsink-fragment-closed" => {
                             let s = msg.structure().unwrap();
                             if let Ok(fragment_closed_at) = s.get::<gst::ClockTime>("running-time")
                             {
-                                self.write_playlist(element, Some(fragment_closed_at))
-                                    .unwrap();
+                                let fragment_opened_at = match &*self.state.lock().unwrap() {
+                                    State::Started {
+                                        fragment_opened_at, ..
+                                    } => *fragment_opened_at,
+                                    State::Stopped => None,
+                                };
+
+                                // The failure is posted as an error message.
+                                let _ = self.write_playlist(element, Some(fragment_closed_at));
+
+                                // Subtitle segments share the boundaries of the video segments.
+                                if let (Some(opened_at), Some(closed_at)) = (
+                                    fragment_opened_at.and_then(|time| time.nseconds()),
+                                    fragment_closed_at.nseconds(),
+                                ) {
+                                    self.write_subtitles(element, Some((opened_at, closed_at)));
+                                }
                             }
                         }
                         _ => {}
@@ -423,6 +3303,13 @@ impl ObjectImpl for FlexHlsSink {
                     Some(DEFAULT_LOCATION),
                     glib::ParamFlags::READWRITE,
                 ),
+                glib::ParamSpec::new_string(
+                    "init-location",
+                    "Init Segment Location",
+                    "Location of the init segment file to write when producing fragmented MP4 segments",
+                    Some(DEFAULT_INIT_LOCATION),
+                    glib::ParamFlags::READWRITE,
+                ),
                 glib::ParamSpec::new_string(
                     "playlist-location",
                     "Playlist Location",
@@ -430,10 +3317,31 @@ impl ObjectImpl for FlexHlsSink {
                     Some(DEFAULT_PLAYLIST_LOCATION),
                     glib::ParamFlags::READWRITE,
                 ),
+                glib::ParamSpec::new_string(
+                    "iframe-playlist-location",
+                    "I-frame Playlist Location",
+                    "Location of the I-frame only playlist to write for trick play. (NULL - disabled)",
+                    None,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_string(
+                    "subtitle-location",
+                    "Subtitle File Location",
+                    "Location of the WebVTT segment files to write, %u is replaced by the subtitle pad index",
+                    Some(DEFAULT_SUBTITLE_LOCATION),
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_string(
+                    "subtitle-playlist-location",
+                    "Subtitle Playlist Location",
+                    "Location of the subtitle playlists to write, %u is replaced by the subtitle pad index",
+                    Some(DEFAULT_SUBTITLE_PLAYLIST_LOCATION),
+                    glib::ParamFlags::READWRITE,
+                ),
                 glib::ParamSpec::new_string(
                     "playlist-root",
                     "Playlist Root",
-                    "Location of the playlist to write.",
+                    "Base path or URL of the segments referenced by the playlist, the segment file names are appended to it.",
                     None,
                     glib::ParamFlags::READWRITE,
                 ),
@@ -471,6 +3379,148 @@ impl ObjectImpl for FlexHlsSink {
                     DEFAULT_SEND_KEYFRAME_REQUESTS,
                     glib::ParamFlags::READWRITE,
                 ),
+                glib::ParamSpec::new_enum(
+                    "segment-format",
+                    "Segment Format",
+                    "Container format of the segments. Fragmented MP4 segments reference a separate init segment written to init-location, the segment location should be changed accordingly (e.g. segment%05d.m4s). Packed audio segments hold the ADTS AAC or MP3 stream of a single audio pad (e.g. segment%05d.aac).",
+                    SegmentFormat::static_type(),
+                    DEFAULT_SEGMENT_FORMAT as i32,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_uint(
+                    "part-duration",
+                    "Part duration",
+                    "The target duration in milliseconds of the partial segments of a Low-Latency HLS playlist. The playlist advertises blocking reloads, it must be served by an origin supporting the _HLS_msn and _HLS_part query parameters. (0 - disabled)",
+                    0,
+                    u32::MAX,
+                    DEFAULT_PART_DURATION,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_enum(
+                    "encryption",
+                    "Encryption",
+                    "Encryption method of the segments. Keys are requested with the get-key signal and announced with the key-rotated signal. Not supported with fMP4 segments, single-file, I-frame playlists or partial segments.",
+                    Encryption::static_type(),
+                    DEFAULT_ENCRYPTION as i32,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_string(
+                    "key-location",
+                    "Key Location",
+                    "Location of the key files to write when encrypting segments",
+                    Some(DEFAULT_KEY_LOCATION),
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_uint(
+                    "key-rotation",
+                    "Key rotation",
+                    "Number of segments encrypted with the same key before rotating to a new key. (0 - never rotate)",
+                    0,
+                    u32::MAX,
+                    DEFAULT_KEY_ROTATION,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_enum(
+                    "playlist-type",
+                    "Playlist type",
+                    "Type of the playlist. EVENT playlists keep all segments, VOD playlists are written once at EOS.",
+                    PlaylistType::static_type(),
+                    DEFAULT_PLAYLIST_TYPE as i32,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_boolean(
+                    "single-file",
+                    "Single file",
+                    "Append all segments to the file of the first segment, addressing them with EXT-X-BYTERANGE",
+                    DEFAULT_SINGLE_FILE,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_boolean(
+                    "cue-tags",
+                    "Cue tags",
+                    "Also mark the SCTE-35 splice points with EXT-X-CUE-OUT and EXT-X-CUE-IN, next to EXT-X-DATERANGE",
+                    DEFAULT_CUE_TAGS,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_boolean(
+                    "resume",
+                    "Resume",
+                    "Continue the playlist found at playlist-location when starting, keeping its segments and sequence numbers. The playlist is read back from the storage, or from the filesystem without one, starting fails when it can not be read",
+                    DEFAULT_RESUME,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_string(
+                    "upload-url",
+                    "Upload URL",
+                    "Base URL the segments and playlists are uploaded to with HTTP PUT, and deleted from with HTTP DELETE, instead of emitting the stream signals",
+                    None,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_uint(
+                    "upload-retries",
+                    "Upload retries",
+                    "Number of times a failed upload is retried, with a growing delay, before posting an error",
+                    0,
+                    u32::MAX,
+                    DEFAULT_UPLOAD_RETRIES,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_uint(
+                    "upload-queue-size",
+                    "Upload queue size",
+                    "Maximum number of uploads waiting to be sent, closing more segments waits up to 10 seconds for one to be sent, the upload then fails",
+                    1,
+                    u32::MAX,
+                    DEFAULT_UPLOAD_QUEUE_SIZE,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_enum(
+                    "target-duration-policy",
+                    "Target duration policy",
+                    "What to do when a segment, its duration rounded to the nearest second, lasts longer than the target duration of the playlist. A warning is posted in any case",
+                    TargetDurationPolicy::static_type(),
+                    DEFAULT_TARGET_DURATION_POLICY as i32,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_boxed(
+                    "stats",
+                    "Statistics",
+                    "Statistics of the segments and playlists written since the element started",
+                    gst::Structure::static_type(),
+                    glib::ParamFlags::READABLE,
+                ),
+                glib::ParamSpec::new_uint(
+                    "write-queue-size",
+                    "Write queue size",
+                    "Maximum number of writes (segment data chunks, playlists and deletions) waiting for a dedicated writer thread, 0 to write from the streaming thread. The stream signals are then emitted from the writer thread",
+                    0,
+                    u32::MAX,
+                    DEFAULT_WRITE_QUEUE_SIZE,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_enum(
+                    "write-queue-full",
+                    "Write queue full",
+                    "What to do when the write queue is full",
+                    WriteQueueFull::static_type(),
+                    DEFAULT_WRITE_QUEUE_FULL as i32,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_boolean(
+                    "align-to-epoch",
+                    "Align to epoch",
+                    "Split the segments at the multiples of target-duration since the UNIX epoch, on the wall clock used for program-date-time, and number them after these intervals",
+                    DEFAULT_ALIGN_TO_EPOCH,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_enum(
+                    "program-date-time",
+                    "Program date time",
+                    "Source of the wall-clock time written as EXT-X-PROGRAM-DATE-TIME on each segment",
+                    ProgramDateTime::static_type(),
+                    DEFAULT_PROGRAM_DATE_TIME as i32,
+                    glib::ParamFlags::READWRITE,
+                ),
             ]
         });
 
@@ -496,11 +3546,43 @@ impl ObjectImpl for FlexHlsSink {
 
                     Some(
                         flexhlssink
-                            .new_file_stream(&element, &playlist_location)
+                            .new_playlist_stream(&element, &playlist_location)
                             .ok()?
                             .to_value(),
                     )
                 })
+                .accumulator(|_hint, ret, value| {
+                    // First signal handler wins
+                    *ret = value.clone();
+                    false
+                })
+                .build(),
+                glib::subclass::Signal::builder(
+                    SIGNAL_COMMIT_PLAYLIST_STREAM,
+                    &[String::static_type().into()],
+                    bool::static_type().into(),
+                )
+                .action()
+                .class_handler(|_, args| {
+                    let element = args[0]
+                        .get::<super::FlexHlsSink>()
+                        .expect("commit-playlist-stream signal arg");
+                    let playlist_location = args[1]
+                        .get::<String>()
+                        .expect("commit-playlist-stream signal arg");
+                    let flexhlssink = FlexHlsSink::from_instance(&element);
+
+                    Some(
+                        flexhlssink
+                            .commit_playlist(&element, &playlist_location)
+                            .to_value(),
+                    )
+                })
+                .accumulator(|_hint, ret, value| {
+                    // Every handler commits, until one fails
+                    *ret = value.clone();
+                    value.get::<bool>().unwrap_or(false)
+                })
                 .build(),
                 glib::subclass::Signal::builder(
                     SIGNAL_GET_FRAGMENT_STREAM,
@@ -523,6 +3605,11 @@ impl ObjectImpl for FlexHlsSink {
                             .to_value(),
                     )
                 })
+                .accumulator(|_hint, ret, value| {
+                    // First signal handler wins
+                    *ret = value.clone();
+                    false
+                })
                 .build(),
                 glib::subclass::Signal::builder(
                     SIGNAL_DELETE_FRAGMENT,
@@ -539,6 +3626,88 @@ impl ObjectImpl for FlexHlsSink {
                     None
                 })
                 .build(),
+                glib::subclass::Signal::builder(
+                    SIGNAL_GET_KEY,
+                    &[u32::static_type().into(), String::static_type().into()],
+                    glib::Bytes::static_type().into(),
+                )
+                .action()
+                .class_handler(|_, args| {
+                    let element = args[0]
+                        .get::<super::FlexHlsSink>()
+                        .expect("get-key signal arg");
+                    let key_location = args[2].get::<String>().expect("get-key signal arg");
+                    let flexhlssink = FlexHlsSink::from_instance(&element);
+
+                    match flexhlssink.new_key(&element, &key_location) {
+                        Ok(key) => Some(key.to_value()),
+                        Err(err) => {
+                            gst_error!(CAT, obj: &element, "Could not create key: {}", err);
+                            None
+                        }
+                    }
+                })
+                .accumulator(|_hint, ret, value| {
+                    // First signal handler wins
+                    *ret = value.clone();
+                    false
+                })
+                .build(),
+                glib::subclass::Signal::builder(
+                    SIGNAL_INSERT_METADATA,
+                    &[gst::TagList::static_type().into()],
+                    bool::static_type().into(),
+                )
+                .action()
+                .class_handler(|_, args| {
+                    let element = args[0]
+                        .get::<super::FlexHlsSink>()
+                        .expect("insert-metadata signal arg");
+                    let tags = args[1]
+                        .get::<gst::TagList>()
+                        .expect("insert-metadata signal arg");
+                    let flexhlssink = FlexHlsSink::from_instance(&element);
+
+                    Some(flexhlssink.insert_metadata(&element, &tags).to_value())
+                })
+                .build(),
+                glib::subclass::Signal::builder(
+                    SIGNAL_SPLIT_NOW,
+                    &[],
+                    glib::types::Type::UNIT.into(),
+                )
+                .action()
+                .class_handler(|_, args| {
+                    let element = args[0]
+                        .get::<super::FlexHlsSink>()
+                        .expect("split-now signal arg");
+                    let flexhlssink = FlexHlsSink::from_instance(&element);
+
+                    flexhlssink.split_now(&element);
+                    None
+                })
+                .build(),
+                glib::subclass::Signal::builder(
+                    SIGNAL_KEY_ROTATED,
+                    &[u32::static_type().into(), String::static_type().into()],
+                    String::static_type().into(),
+                )
+                .class_handler(|_, args| {
+                    let element = args[0]
+                        .get::<super::FlexHlsSink>()
+                        .expect("key-rotated signal arg");
+                    let key_location = args[2].get::<String>().expect("key-rotated signal arg");
+                    let flexhlssink = FlexHlsSink::from_instance(&element);
+
+                    let settings = flexhlssink.settings.lock().unwrap();
+                    Some(settings.uri_for(&key_location).to_value())
+                })
+                .accumulator(|_hint, ret, value| {
+                    // First signal handler wins
+                    *ret = value.clone();
+                    false
+                })
+                .build(),
             ]
         });
 
@@ -565,12 +3734,35 @@ impl ObjectImpl for FlexHlsSink {
                         .unwrap();
                 }
             }
+            "init-location" => {
+                settings.init_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_INIT_LOCATION.into());
+            }
             "playlist-location" => {
                 settings.playlist_location = value
                     .get::<Option<String>>()
                     .expect("type checked upstream")
                     .unwrap_or_else(|| DEFAULT_LOCATION.into());
             }
+            "iframe-playlist-location" => {
+                settings.iframe_playlist_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+            }
+            "subtitle-location" => {
+                settings.subtitle_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_SUBTITLE_LOCATION.into());
+            }
+            "subtitle-playlist-location" => {
+                settings.subtitle_playlist_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_SUBTITLE_PLAYLIST_LOCATION.into());
+            }
             "playlist-root" => {
                 settings.playlist_root = value
                     .get::<Option<String>>()
@@ -584,12 +3776,12 @@ impl ObjectImpl for FlexHlsSink {
                 settings.target_duration = value.get().expect("type checked upstream");
                 if let Some(splitmuxsink) = &settings.splitmuxsink {
                     splitmuxsink
-                        .set_property(
-                            "max-size-time",
-                            &((settings.target_duration as u64) * gst::SECOND_VAL),
-                        )
+                        .set_property("max-size-time", &settings.max_size_time())
                         .unwrap();
                 }
+                if settings.segment_format == SegmentFormat::Fmp4 {
+                    settings.configure_muxer();
+                }
             }
             "playlist-length" => {
                 settings.playlist_length = value.get().expect("type checked upstream");
@@ -602,6 +3794,82 @@ impl ObjectImpl for FlexHlsSink {
                         .unwrap();
                 }
             }
+            "segment-format" => {
+                settings.segment_format =
+                    value.get::<SegmentFormat>().expect("type checked upstream");
+                settings.configure_muxer();
+            }
+            "part-duration" => {
+                settings.part_duration = value.get().expect("type checked upstream");
+            }
+            "encryption" => {
+                settings.encryption = value.get::<Encryption>().expect("type checked upstream");
+            }
+            "key-location" => {
+                settings.key_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_KEY_LOCATION.into());
+            }
+            "key-rotation" => {
+                settings.key_rotation = value.get().expect("type checked upstream");
+            }
+            "playlist-type" => {
+                settings.playlist_type =
+                    value.get::<PlaylistType>().expect("type checked upstream");
+            }
+            "single-file" => {
+                settings.single_file = value.get().expect("type checked upstream");
+                if let Some(giostreamsink) = &settings.giostreamsink {
+                    // The output file stays open until the element stops.
+                    giostreamsink
+                        .set_property("close-on-stop", &!settings.single_file)
+                        .unwrap();
+                }
+            }
+            "cue-tags" => {
+                settings.cue_tags = value.get().expect("type checked upstream");
+            }
+            "resume" => {
+                settings.resume = value.get().expect("type checked upstream");
+            }
+            "upload-url" => {
+                settings.upload_url = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+            }
+            "upload-retries" => {
+                settings.upload_retries = value.get().expect("type checked upstream");
+            }
+            "upload-queue-size" => {
+                settings.upload_queue_size = value.get().expect("type checked upstream");
+            }
+            "write-queue-size" => {
+                settings.write_queue_size = value.get().expect("type checked upstream");
+            }
+            "write-queue-full" => {
+                settings.write_queue_full = value
+                    .get::<WriteQueueFull>()
+                    .expect("type checked upstream");
+            }
+            "target-duration-policy" => {
+                settings.target_duration_policy = value
+                    .get::<TargetDurationPolicy>()
+                    .expect("type checked upstream");
+            }
+            "align-to-epoch" => {
+                settings.align_to_epoch = value.get().expect("type checked upstream");
+                if let Some(splitmuxsink) = &settings.splitmuxsink {
+                    splitmuxsink
+                        .set_property("max-size-time", &settings.max_size_time())
+                        .unwrap();
+                }
+            }
+            "program-date-time" => {
+                settings.program_date_time = value
+                    .get::<ProgramDateTime>()
+                    .expect("type checked upstream");
+            }
             _ => unimplemented!(),
         };
     }
@@ -610,7 +3878,11 @@ impl ObjectImpl for FlexHlsSink {
         let settings = self.settings.lock().unwrap();
         match pspec.name() {
             "location" => settings.location.to_value(),
+            "init-location" => settings.init_location.to_value(),
             "playlist-location" => settings.playlist_location.to_value(),
+            "iframe-playlist-location" => settings.iframe_playlist_location.to_value(),
+            "subtitle-location" => settings.subtitle_location.to_value(),
+            "subtitle-playlist-location" => settings.subtitle_playlist_location.to_value(),
             "playlist-root" => settings.playlist_root.to_value(),
             "max-files" => {
                 let max_files = settings.max_num_segment_files as u32;
@@ -619,6 +3891,24 @@ impl ObjectImpl for FlexHlsSink {
             "target-duration" => settings.target_duration.to_value(),
             "playlist-length" => settings.playlist_length.to_value(),
             "send-keyframe-requests" => settings.send_keyframe_requests.to_value(),
+            "segment-format" => settings.segment_format.to_value(),
+            "part-duration" => settings.part_duration.to_value(),
+            "encryption" => settings.encryption.to_value(),
+            "key-location" => settings.key_location.to_value(),
+            "key-rotation" => settings.key_rotation.to_value(),
+            "playlist-type" => settings.playlist_type.to_value(),
+            "single-file" => settings.single_file.to_value(),
+            "cue-tags" => settings.cue_tags.to_value(),
+            "align-to-epoch" => settings.align_to_epoch.to_value(),
+            "resume" => settings.resume.to_value(),
+            "upload-url" => settings.upload_url.to_value(),
+            "upload-retries" => settings.upload_retries.to_value(),
+            "upload-queue-size" => settings.upload_queue_size.to_value(),
+            "write-queue-size" => settings.write_queue_size.to_value(),
+            "write-queue-full" => settings.write_queue_full.to_value(),
+            "target-duration-policy" => settings.target_duration_policy.to_value(),
+            "stats" => self.stats.lock().unwrap().to_structure().to_value(),
+            "program-date-time" => settings.program_date_time.to_value(),
             _ => unimplemented!(),
         }
     }
@@ -632,22 +3922,21 @@ impl ObjectImpl for FlexHlsSink {
             .expect("Could not make element splitmuxsink");
         let giostreamsink = gst::ElementFactory::make("giostreamsink", Some("giostream_sink"))
             .expect("Could not make element giostreamsink");
+        // Close each fragment stream as soon as the fragment is complete, so streams wrapping
+        // the one returned by `get-fragment-stream` get to finish their output.
+        giostreamsink.set_property("close-on-stop", &true).unwrap();
 
-        let mux = gst::ElementFactory::make("mpegtsmux", Some("mpeg-ts_mux"))
-            .expect("Could not make element mpegtsmux");
+        let mux = settings.segment_format.make_muxer(settings.target_duration);
 
         let location: Option<String> = None;
         splitmuxsink
             .set_properties(&[
                 ("location", &location),
-                (
-                    "max-size-time",
-                    &((settings.target_duration as u64) * gst::SECOND_VAL),
-                ),
+                ("max-size-time", &settings.max_size_time()),
                 ("send-keyframe-requests", &true),
                 ("muxer", &mux),
                 ("sink", &giostreamsink),
-                ("reset-muxer", &false),
+                ("reset-muxer", &settings.segment_format.reset_muxer()),
             ])
             .unwrap();
 
@@ -657,13 +3946,14 @@ impl ObjectImpl for FlexHlsSink {
         let this = self.clone();
         let element_weak = obj.downgrade();
         splitmuxsink
-            .connect("format-location", false, move |args| {
+            .connect("format-location-full", false, move |args| {
                 let fragment_id = args[1].get::<u32>().unwrap();
+                let first_sample = args[2].get::<Option<gst::Sample>>().unwrap();
 
                 gst_info!(CAT, "Got fragment-id: {}", fragment_id);
 
                 let element = element_weak.upgrade().unwrap();
-                match this.on_format_location(&element, fragment_id) {
+                match this.on_format_location(&element, fragment_id, first_sample) {
                     Ok(segment_location) => Some(segment_location.to_value()),
                     Err(err) => {
                         gst_error!(CAT, "on format-location handler: {}", err);
@@ -673,6 +3963,26 @@ impl ObjectImpl for FlexHlsSink {
             })
             .unwrap();
 
+        let this = self.clone();
+        let element_weak = obj.downgrade();
+        giostreamsink
+            .static_pad("sink")
+            .unwrap()
+            .add_probe(gst::PadProbeType::BUFFER, move |_pad, info| {
+                let element = match element_weak.upgrade() {
+                    Some(element) => element,
+                    None => return gst::PadProbeReturn::Ok,
+                };
+                match info.data {
+                    Some(gst::PadProbeData::Buffer(ref mut buffer)) => {
+                        this.prepend_timestamp_tag(buffer);
+                        this.on_muxed_buffer(&element, buffer)
+                    }
+                    _ => gst::PadProbeReturn::Ok,
+                }
+            })
+            .unwrap();
+
         settings.splitmuxsink = Some(splitmuxsink);
         settings.giostreamsink = Some(giostreamsink);
     }
@@ -707,6 +4017,16 @@ impl ElementImpl for FlexHlsSink {
 
             let caps = gst::Caps::new_any();
             let audio_pad_template = gst::PadTemplate::new(
+                "audio_%u",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Request,
+                &caps,
+            )
+            .unwrap();
+
+            // The single audio pad of the earlier versions of the element.
+            let caps = gst::Caps::new_any();
+            let single_audio_pad_template = gst::PadTemplate::new(
                 "audio",
                 gst::PadDirection::Sink,
                 gst::PadPresence::Request,
@@ -714,12 +4034,47 @@ impl ElementImpl for FlexHlsSink {
             )
             .unwrap();
 
-            vec![video_pad_template, audio_pad_template]
+            let mut caps = gst::Caps::builder("text/x-raw").build();
+            caps.get_mut()
+                .unwrap()
+                .append(gst::Caps::builder("application/x-subtitle-vtt").build());
+            let subtitle_pad_template = gst::PadTemplate::new(
+                "subtitle_%u",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Request,
+                &caps,
+            )
+            .unwrap();
+
+            let caps = gst::Caps::builder("meta/x-id3").build();
+            let meta_pad_template = gst::PadTemplate::new(
+                "meta",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Request,
+                &caps,
+            )
+            .unwrap();
+
+            vec![
+                video_pad_template,
+                audio_pad_template,
+                single_audio_pad_template,
+                subtitle_pad_template,
+                meta_pad_template,
+            ]
         });
 
         PAD_TEMPLATES.as_ref()
     }
 
+    fn send_event(&self, element: &Self::Type, event: gst::Event) -> bool {
+        if is_split_now_event(&event) {
+            self.split_now(element);
+            return true;
+        }
+        self.parent_send_event(element, event)
+    }
+
     fn change_state(
         &self,
         element: &Self::Type,
@@ -755,6 +4110,7 @@ impl ElementImpl for FlexHlsSink {
 
                 if write_final {
                     self.write_final_playlist(element)?;
+                    self.write_subtitles(element, None);
                 }
             }
             gst::StateChange::ReadyToNull => {
@@ -770,32 +4126,57 @@ impl ElementImpl for FlexHlsSink {
         &self,
         element: &Self::Type,
         templ: &gst::PadTemplate,
-        _name: Option<String>,
+        name: Option<String>,
         _caps: Option<&gst::Caps>,
     ) -> Option<gst::Pad> {
         let mut settings = self.settings.lock().unwrap();
         match templ.name_template().as_ref().map(|val| val.as_str()) {
-            Some("audio") => {
-                if settings.audio_sink {
+            Some(name_template @ "audio_%u") | Some(name_template @ "audio") => {
+                let index = match name_template {
+                    "audio" => None,
+                    _ => Some(
+                        name.as_ref()
+                            .and_then(|name| name.strip_prefix("audio_"))
+                            .and_then(|index| index.parse::<u32>().ok())
+                            .unwrap_or(settings.next_audio_index),
+                    ),
+                };
+                let pad_name = match index {
+                    Some(index) => format!("audio_{}", index),
+                    None => String::from("audio"),
+                };
+                // All streams are muxed into the same segments, alternate audio renditions each
+                // need a media playlist of their own, as written by `flexhlsmastersink`.
+                if settings.audio_sinks > 0 {
                     gst_debug!(
                         CAT,
                         obj: element,
-                        "requested_new_pad: audio pad is already set"
+                        "requested_new_pad: audio pad is already set, use flexhlsmastersink for \
+                        alternate audio renditions"
                     );
                     return None;
                 }
 
+                let muxer_pad_name = format!("audio_{}", settings.next_muxer_audio_index);
                 let splitmuxsink = match &mut settings.splitmuxsink {
                     None => return None,
                     Some(sms) => sms,
                 };
-                let peer_pad = splitmuxsink.request_pad_simple("audio_0").unwrap();
+                let peer_pad = splitmuxsink.request_pad_simple(&muxer_pad_name)?;
+                settings.next_muxer_audio_index += 1;
                 let sink_pad =
-                    gst::GhostPad::from_template_with_target(&templ, Some("audio"), &peer_pad)
+                    gst::GhostPad::from_template_with_target(&templ, Some(&pad_name), &peer_pad)
                         .unwrap();
+                self.add_discontinuity_probe(element, &sink_pad);
+                self.add_reference_timestamp_probe(&sink_pad);
+                self.add_meta_probe(element, &sink_pad);
+                self.add_custom_event_probe(element, &sink_pad);
                 element.add_pad(&sink_pad).unwrap();
                 sink_pad.set_active(true).unwrap();
-                settings.audio_sink = true;
+                settings.audio_sinks += 1;
+                if let Some(index) = index {
+                    settings.next_audio_index = settings.next_audio_index.max(index + 1);
+                }
 
                 Some(sink_pad.upcast())
             }
@@ -817,12 +4198,91 @@ impl ElementImpl for FlexHlsSink {
                 let sink_pad =
                     gst::GhostPad::from_template_with_target(&templ, Some("video"), &peer_pad)
                         .unwrap();
+                self.add_discontinuity_probe(element, &sink_pad);
+                self.add_reference_timestamp_probe(&sink_pad);
+                self.add_meta_probe(element, &sink_pad);
+                self.add_custom_event_probe(element, &sink_pad);
                 element.add_pad(&sink_pad).unwrap();
                 sink_pad.set_active(true).unwrap();
                 settings.video_sink = true;
 
                 Some(sink_pad.upcast())
             }
+            Some("meta") => {
+                let mut meta = self.meta.lock().unwrap();
+                if meta.is_some() {
+                    gst_debug!(
+                        CAT,
+                        obj: element,
+                        "requested_new_pad: meta pad is already set"
+                    );
+                    return None;
+                }
+                if settings.segment_format != SegmentFormat::Ts {
+                    gst_debug!(
+                        CAT,
+                        obj: element,
+                        "requested_new_pad: timed metadata is only muxed into MPEG-TS segments"
+                    );
+                    return None;
+                }
+
+                let muxer_pad_name = format!("audio_{}", settings.next_muxer_audio_index);
+                let splitmuxsink = match &mut settings.splitmuxsink {
+                    None => return None,
+                    Some(sms) => sms,
+                };
+                // The metadata is muxed as an ID3 stream (stream type 0x15) next to the audio.
+                let peer_pad = splitmuxsink.request_pad_simple(&muxer_pad_name)?;
+                settings.next_muxer_audio_index += 1;
+                let sink_pad =
+                    gst::GhostPad::from_template_with_target(&templ, Some("meta"), &peer_pad)
+                        .unwrap();
+                element.add_pad(&sink_pad).unwrap();
+                sink_pad.set_active(true).unwrap();
+                *meta = Some(MetaStream {
+                    pad: sink_pad.clone(),
+                    position: None,
+                });
+
+                Some(sink_pad.upcast())
+            }
+            Some("subtitle_%u") => {
+                let index = name
+                    .as_ref()
+                    .and_then(|name| name.strip_prefix("subtitle_"))
+                    .and_then(|index| index.parse::<u32>().ok())
+                    .unwrap_or(settings.next_subtitle_index);
+                settings.next_subtitle_index = settings.next_subtitle_index.max(index + 1);
+
+                let sink_pad =
+                    gst::Pad::builder_with_template(templ, Some(&format!("subtitle_{}", index)))
+                        .chain_function(|pad, parent, buffer| {
+                            FlexHlsSink::catch_panic_pad_function(
+                                parent,
+                                || Err(gst::FlowError::Error),
+                                |flexhlssink, _element| flexhlssink.subtitle_chain(pad, buffer),
+                            )
+                        })
+                        .event_function(|pad, parent, event| {
+                            FlexHlsSink::catch_panic_pad_function(
+                                parent,
+                                || false,
+                                |flexhlssink, _element| flexhlssink.subtitle_event(pad, event),
+                            )
+                        })
+                        .build();
+
+                self.subtitles.lock().unwrap().push(SubtitleTrack::new(
+                    sink_pad.clone(),
+                    index,
+                    &settings,
+                ));
+                sink_pad.set_active(true).unwrap();
+                element.add_pad(&sink_pad).unwrap();
+
+                Some(sink_pad)
+            }
             None => {
                 gst_debug!(CAT, obj: element, "template name returned `None`",);
                 None
@@ -842,7 +4302,40 @@ impl ElementImpl for FlexHlsSink {
     fn release_pad(&self, element: &Self::Type, pad: &gst::Pad) {
         let mut settings = self.settings.lock().unwrap();
 
-        if !settings.audio_sink && !settings.video_sink {
+        let subtitle_track = {
+            let mut subtitles = self.subtitles.lock().unwrap();
+            subtitles
+                .iter()
+                .position(|track| &track.pad == pad)
+                .map(|idx| subtitles.remove(idx))
+        };
+        if subtitle_track.is_some() {
+            pad.set_active(false).unwrap();
+            element.remove_pad(pad).unwrap();
+            return;
+        }
+
+        let meta_stream = {
+            let mut meta = self.meta.lock().unwrap();
+            match &*meta {
+                Some(stream) if &stream.pad == pad => meta.take(),
+                _ => None,
+            }
+        };
+        if let Some(meta_stream) = meta_stream {
+            if let Some(peer) = meta_stream.pad.target() {
+                settings
+                    .splitmuxsink
+                    .as_ref()
+                    .unwrap()
+                    .release_request_pad(&peer);
+            }
+            pad.set_active(false).unwrap();
+            element.remove_pad(pad).unwrap();
+            return;
+        }
+
+        if settings.audio_sinks == 0 && !settings.video_sink {
             return;
         }
 
@@ -858,8 +4351,8 @@ impl ElementImpl for FlexHlsSink {
         pad.set_active(false).unwrap();
         element.remove_pad(pad).unwrap();
 
-        if "audio" == ghost_pad.name() {
-            settings.audio_sink = false;
+        if ghost_pad.name().starts_with("audio") {
+            settings.audio_sinks -= 1;
         } else {
             settings.video_sink = false;
         }
@@ -1,7 +1,20 @@
 use glib::prelude::*;
+use glib::subclass::prelude::*;
 
+mod codecs;
+mod encryption;
+mod http;
+mod id3;
 mod imp;
+mod master;
 mod playlist;
+mod storage;
+mod webvtt;
+mod writer;
+
+pub use http::HttpStorage;
+pub use master::FlexHlsMasterSink;
+pub use storage::{FsStorage, HlsStorage, MemoryStorage};
 
 glib::wrapper! {
     pub struct FlexHlsSink(ObjectSubclass<imp::FlexHlsSink>) @extends gst::Bin, gst::Element, gst::Object;
@@ -10,6 +23,15 @@ glib::wrapper! {
 unsafe impl Send for FlexHlsSink {}
 unsafe impl Sync for FlexHlsSink {}
 
+impl FlexHlsSink {
+    /// Stores the segments and playlists in `storage`, instead of emitting the
+    /// `get-fragment-stream`, `get-playlist-stream`, `commit-playlist-stream` and
+    /// `delete-fragment` signals.
+    pub fn set_storage<S: HlsStorage + 'static>(&self, storage: S) {
+        imp::FlexHlsSink::from_instance(self).set_storage(std::sync::Arc::new(storage));
+    }
+}
+
 pub fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
     gst::Element::register(
         Some(plugin),
@@ -17,6 +39,12 @@ pub fn plugin_init(plugin: &gst::Plugin) -> Result<(), glib::BoolError> {
         gst::Rank::None,
         FlexHlsSink::static_type(),
     )?;
+    gst::Element::register(
+        Some(plugin),
+        "flexhlsmastersink",
+        gst::Rank::None,
+        FlexHlsMasterSink::static_type(),
+    )?;
 
     Ok(())
 }
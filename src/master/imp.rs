@@ -0,0 +1,1041 @@
+use crate::codecs::codec_string;
+use crate::storage::{FsStorage, HlsStorage};
+use gio::prelude::*;
+use glib::subclass::prelude::*;
+use gst::prelude::*;
+use gst::subclass::prelude::*;
+use gst::{gst_debug, gst_error, gst_info, gst_warning};
+use m3u8_rs::playlist::{AlternativeMedia, AlternativeMediaType, MasterPlaylist, VariantStream};
+use once_cell::sync::Lazy;
+use std::path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+const DEFAULT_LOCATION: &str = "video%u_segment%05d.ts";
+const DEFAULT_PLAYLIST_LOCATION: &str = "video%u.m3u8";
+const DEFAULT_MASTER_PLAYLIST_LOCATION: &str = "master.m3u8";
+const DEFAULT_AUDIO_LOCATION: &str = "audio%u_segment%05d.ts";
+const DEFAULT_AUDIO_PLAYLIST_LOCATION: &str = "audio%u.m3u8";
+const DEFAULT_MAX_NUM_SEGMENT_FILES: u32 = 10;
+const DEFAULT_TARGET_DURATION: u32 = 15;
+const DEFAULT_PLAYLIST_LENGTH: u32 = 5;
+
+const GST_M3U8_MASTER_PLAYLIST_VERSION: usize = 3;
+const PAD_INDEX_PLACEHOLDER: &str = "%u";
+const AUDIO_GROUP_ID: &str = "audio";
+
+const SIGNAL_GET_MASTER_PLAYLIST_STREAM: &str = "get-master-playlist-stream";
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "flexhlsmastersink",
+        gst::DebugColorFlags::empty(),
+        Some("Flexible HLS multivariant sink"),
+    )
+});
+
+struct Settings {
+    location: String,
+    playlist_location: String,
+    master_playlist_location: String,
+    iframe_playlist_location: Option<String>,
+    audio_location: String,
+    audio_playlist_location: String,
+    playlist_length: u32,
+    max_num_segment_files: u32,
+    target_duration: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            location: String::from(DEFAULT_LOCATION),
+            playlist_location: String::from(DEFAULT_PLAYLIST_LOCATION),
+            master_playlist_location: String::from(DEFAULT_MASTER_PLAYLIST_LOCATION),
+            iframe_playlist_location: None,
+            audio_location: String::from(DEFAULT_AUDIO_LOCATION),
+            audio_playlist_location: String::from(DEFAULT_AUDIO_PLAYLIST_LOCATION),
+            playlist_length: DEFAULT_PLAYLIST_LENGTH,
+            max_num_segment_files: DEFAULT_MAX_NUM_SEGMENT_FILES,
+            target_duration: DEFAULT_TARGET_DURATION,
+        }
+    }
+}
+
+/// Bitrate of a variant, measured on the segments written by its sink, as they are fetched.
+#[derive(Default)]
+struct Bitrate {
+    total_bytes: u64,
+    total_duration: u64,
+    peak: u64,
+}
+
+impl Bitrate {
+    /// Accounts for a segment of `size` bytes lasting `duration` nanoseconds, returns `true`
+    /// when the peak bitrate changed.
+    fn push(&mut self, size: u64, duration: u64) -> bool {
+        if duration == 0 {
+            return false;
+        }
+
+        self.total_bytes += size;
+        self.total_duration += duration;
+
+        let bitrate = bits_per_second(size, duration);
+        if bitrate > self.peak {
+            self.peak = bitrate;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn average(&self) -> Option<u64> {
+        if self.total_duration == 0 {
+            return None;
+        }
+        Some(bits_per_second(self.total_bytes, self.total_duration))
+    }
+}
+
+fn bits_per_second(size: u64, duration: u64) -> u64 {
+    (size as u128 * 8 * gst::SECOND_VAL as u128 / duration as u128) as u64
+}
+
+struct Variant {
+    pad_name: String,
+    sink: gst::Element,
+    playlist_location: String,
+    iframe_playlist_location: Option<String>,
+    caps: Option<gst::Caps>,
+    bitrate: Bitrate,
+    /// Bitrate of the keyframes only, as fetched when playing the I-frame playlist.
+    iframe_bitrate: Bitrate,
+}
+
+impl Variant {
+    /// Describes the variant in the master playlist, once its caps and bitrate are known.
+    fn variant_stream(
+        &self,
+        master_dir: Option<&path::Path>,
+        audio_group: Option<&AudioGroup>,
+    ) -> Option<VariantStream> {
+        if self.bitrate.peak == 0 {
+            return None;
+        }
+        let s = self.caps.as_ref()?.structure(0)?;
+
+        // The bandwidth of a variant includes the audio it is played with.
+        let audio_peak = audio_group.map_or(0, |audio_group| audio_group.peak);
+        let codecs = match (
+            codec_string(s),
+            audio_group.and_then(|group| group.codecs.as_ref()),
+        ) {
+            (Some(video_codecs), Some(audio_codecs)) => {
+                Some(format!("{},{}", video_codecs, audio_codecs))
+            }
+            (video_codecs, _) => video_codecs,
+        };
+
+        Some(VariantStream {
+            uri: relative_uri(master_dir, &self.playlist_location),
+            bandwidth: (self.bitrate.peak + audio_peak).to_string(),
+            average_bandwidth: self.bitrate.average().map(|bitrate| bitrate.to_string()),
+            codecs,
+            audio: audio_group.map(|_| AUDIO_GROUP_ID.to_string()),
+            resolution: match (s.get::<i32>("width"), s.get::<i32>("height")) {
+                (Ok(width), Ok(height)) => Some(format!("{}x{}", width, height)),
+                _ => None,
+            },
+            frame_rate: s
+                .get::<gst::Fraction>("framerate")
+                .ok()
+                .filter(|framerate| *framerate.numer() > 0 && *framerate.denom() > 0)
+                .map(|framerate| {
+                    format!(
+                        "{:.3}",
+                        *framerate.numer() as f64 / *framerate.denom() as f64
+                    )
+                }),
+            ..Default::default()
+        })
+    }
+
+    /// Describes the I-frame playlist of the variant, when it has one.
+    fn iframe_stream(&self, master_dir: Option<&path::Path>) -> Option<VariantStream> {
+        let iframe_playlist_location = self.iframe_playlist_location.as_ref()?;
+        let variant_stream = self.variant_stream(master_dir, None)?;
+        if self.iframe_bitrate.peak == 0 {
+            return None;
+        }
+
+        Some(VariantStream {
+            is_i_frame: true,
+            uri: relative_uri(master_dir, iframe_playlist_location),
+            bandwidth: self.iframe_bitrate.peak.to_string(),
+            average_bandwidth: self
+                .iframe_bitrate
+                .average()
+                .map(|bitrate| bitrate.to_string()),
+            frame_rate: None,
+            ..variant_stream
+        })
+    }
+}
+
+/// Alternate audio rendition (`EXT-X-MEDIA:TYPE=AUDIO`) the video variants are played with.
+struct Rendition {
+    pad_name: String,
+    sink: gst::Element,
+    playlist_location: String,
+    caps: Option<gst::Caps>,
+    bitrate: Bitrate,
+    language: Option<String>,
+    name: Option<String>,
+}
+
+impl Rendition {
+    /// Describes the rendition in the master playlist, once its caps are known.
+    fn alternative_media(&self, master_dir: Option<&path::Path>) -> Option<AlternativeMedia> {
+        self.caps.as_ref()?;
+
+        Some(AlternativeMedia {
+            media_type: AlternativeMediaType::Audio,
+            uri: Some(relative_uri(master_dir, &self.playlist_location)),
+            group_id: AUDIO_GROUP_ID.to_string(),
+            language: self.language.clone(),
+            name: self
+                .name
+                .clone()
+                .or_else(|| self.language.clone())
+                .unwrap_or_else(|| self.pad_name.clone()),
+            autoselect: true,
+            ..Default::default()
+        })
+    }
+}
+
+/// Audio renditions of the group referenced by the video variants.
+struct AudioGroup {
+    codecs: Option<String>,
+    peak: u64,
+}
+
+/// Makes `location` relative to the directory of the master playlist, when it is inside of it.
+fn relative_uri(master_dir: Option<&path::Path>, location: &str) -> String {
+    master_dir
+        .and_then(|master_dir| path::Path::new(location).strip_prefix(master_dir).ok())
+        .and_then(|relative| relative.to_str())
+        .unwrap_or(location)
+        .to_string()
+}
+
+#[derive(Default)]
+struct State {
+    variants: Vec<Variant>,
+    renditions: Vec<Rendition>,
+    next_video_index: u32,
+    next_audio_index: u32,
+}
+
+/// Thread writing the master playlist, so the streaming threads never wait for it.
+struct PlaylistWriter {
+    sender: mpsc::SyncSender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+#[derive(Default, Clone)]
+pub struct FlexHlsMasterSink {
+    settings: Arc<Mutex<Settings>>,
+    state: Arc<Mutex<State>>,
+    writer: Arc<Mutex<Option<PlaylistWriter>>>,
+}
+
+impl FlexHlsMasterSink {
+    /// Accounts for a segment written by the sink of a variant or rendition, from its
+    /// `hls-segment-added` message.
+    fn on_segment_added(&self, src: &gst::Object, s: &gst::StructureRef) -> bool {
+        // Missing segments are not fetched.
+        if s.get::<bool>("gap").unwrap_or(false) {
+            return false;
+        }
+        let duration = match s
+            .get::<gst::ClockTime>("duration")
+            .ok()
+            .and_then(|duration| duration.nseconds())
+        {
+            Some(duration) => duration,
+            None => return false,
+        };
+        let size = s.get::<u64>("size").unwrap_or(0);
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(variant) = state
+            .variants
+            .iter_mut()
+            .find(|variant| variant.sink.upcast_ref::<gst::Object>() == src)
+        {
+            let keyframe_size = s.get::<u64>("keyframe-size").unwrap_or(0);
+            return variant.bitrate.push(size, duration)
+                | variant.iframe_bitrate.push(keyframe_size, duration);
+        }
+
+        match state
+            .renditions
+            .iter_mut()
+            .find(|rendition| rendition.sink.upcast_ref::<gst::Object>() == src)
+        {
+            Some(rendition) => rendition.bitrate.push(size, duration),
+            None => false,
+        }
+    }
+
+    fn on_stream_caps(&self, pad_name: &str, caps: gst::Caps) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let stream_caps = state
+            .variants
+            .iter_mut()
+            .find(|variant| variant.pad_name == pad_name)
+            .map(|variant| &mut variant.caps)
+            .or_else(|| {
+                state
+                    .renditions
+                    .iter_mut()
+                    .find(|rendition| rendition.pad_name == pad_name)
+                    .map(|rendition| &mut rendition.caps)
+            });
+
+        match stream_caps {
+            Some(stream_caps) if stream_caps.as_ref() != Some(&caps) => {
+                *stream_caps = Some(caps);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Names the audio renditions after the language and title tags of their stream.
+    fn on_stream_tags(&self, pad_name: &str, tags: &gst::TagListRef) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let rendition = match state
+            .renditions
+            .iter_mut()
+            .find(|rendition| rendition.pad_name == pad_name)
+        {
+            Some(rendition) => rendition,
+            None => return false,
+        };
+
+        let language = tags
+            .get::<gst::tags::LanguageCode>()
+            .map(|language| language.get().to_string());
+        let name = tags
+            .get::<gst::tags::Title>()
+            .map(|title| title.get().to_string());
+
+        let mut changed = false;
+        if language.is_some() && language != rendition.language {
+            rendition.language = language;
+            changed = true;
+        }
+        if name.is_some() && name != rendition.name {
+            rendition.name = name;
+            changed = true;
+        }
+        changed
+    }
+
+    fn write_master_playlist(&self, element: &super::FlexHlsMasterSink) {
+        let master_playlist_location = {
+            let settings = self.settings.lock().unwrap();
+            settings.master_playlist_location.clone()
+        };
+        let master_dir = path::Path::new(&master_playlist_location)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty());
+
+        let playlist = {
+            let state = self.state.lock().unwrap();
+            let mut alternatives = state
+                .renditions
+                .iter()
+                .filter_map(|rendition| rendition.alternative_media(master_dir))
+                .collect::<Vec<_>>();
+            // The first rendition listed is the default one, whichever pad it comes from.
+            if let Some(alternative) = alternatives.first_mut() {
+                alternative.default = true;
+            }
+            let audio_group = match alternatives.is_empty() {
+                true => None,
+                false => Some(AudioGroup {
+                    codecs: state
+                        .renditions
+                        .iter()
+                        .find_map(|rendition| codec_string(rendition.caps.as_ref()?.structure(0)?)),
+                    peak: state
+                        .renditions
+                        .iter()
+                        .map(|rendition| rendition.bitrate.peak)
+                        .max()
+                        .unwrap_or(0),
+                }),
+            };
+
+            MasterPlaylist {
+                version: GST_M3U8_MASTER_PLAYLIST_VERSION,
+                alternatives,
+                variants: state
+                    .variants
+                    .iter()
+                    .filter_map(|variant| variant.variant_stream(master_dir, audio_group.as_ref()))
+                    .chain(
+                        state
+                            .variants
+                            .iter()
+                            .filter_map(|variant| variant.iframe_stream(master_dir)),
+                    )
+                    .collect(),
+                ..Default::default()
+            }
+        };
+
+        if playlist.variants.is_empty() {
+            gst_debug!(CAT, obj: element, "No variant is ready to be announced yet");
+            return;
+        }
+
+        let mut content = vec![];
+        let res = playlist
+            .write_to(&mut content)
+            .map_err(|err| err.to_string())
+            .and_then(|_| {
+                element
+                    .emit_by_name(
+                        SIGNAL_GET_MASTER_PLAYLIST_STREAM,
+                        &[&master_playlist_location],
+                    )
+                    .map_err(|err| err.to_string())?
+                    .and_then(|value| value.get::<gio::OutputStream>().ok())
+                    .ok_or_else(|| String::from("No stream to write the master playlist"))
+            })
+            .and_then(|stream| {
+                stream
+                    .write_all(&content, gio::NONE_CANCELLABLE)
+                    .and_then(|_| stream.close(gio::NONE_CANCELLABLE))
+                    .map_err(|err| err.to_string())
+            })
+            .and_then(|_| {
+                match FsStorage.commit_playlist(&master_playlist_location) {
+                    // A custom stream provider did not write to the temporary file.
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.to_string()),
+                    _ => Ok(()),
+                }
+            });
+
+        // The master playlist is written again on the next change, failing is not fatal.
+        if let Err(err) = res {
+            let text = format!(
+                "Could not write master playlist {}: {}",
+                master_playlist_location, err
+            );
+            gst_warning!(CAT, obj: element, "{}", text);
+            let _ = element.post_message(
+                gst::message::Warning::builder(gst::ResourceError::Write, &text)
+                    .src(element)
+                    .build(),
+            );
+            return;
+        }
+
+        gst_debug!(CAT, obj: element, "Wrote new master playlist file!");
+    }
+
+    /// Has the master playlist written from the writer thread. Changes made while a write is
+    /// pending are written with it, the playlist being rendered once the thread gets to it.
+    fn schedule_master_playlist(&self, element: &super::FlexHlsMasterSink) {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_none() {
+            let (sender, receiver) = mpsc::sync_channel(1);
+            let element_weak = element.downgrade();
+            let thread = thread::Builder::new()
+                .name("flexhlsmastersink-writer".into())
+                .spawn(move || {
+                    for () in receiver {
+                        match element_weak.upgrade() {
+                            Some(element) => FlexHlsMasterSink::from_instance(&element)
+                                .write_master_playlist(&element),
+                            None => break,
+                        }
+                    }
+                });
+            match thread {
+                Ok(thread) => *writer = Some(PlaylistWriter { sender, thread }),
+                Err(err) => {
+                    gst_error!(CAT, obj: element, "Could not start the writer thread: {}", err);
+                    return;
+                }
+            }
+        }
+
+        // A full queue means a write is already pending.
+        let _ = writer.as_ref().unwrap().sender.try_send(());
+    }
+
+    /// Waits for the pending master playlist write, if any.
+    fn stop_writer(&self) {
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(PlaylistWriter { sender, thread }) = writer {
+            drop(sender);
+            let _ = thread.join();
+        }
+    }
+
+    /// Opens the temporary file the master playlist is written to, which replaces the master
+    /// playlist once complete, so players never read a partially written one.
+    fn new_file_stream(
+        &self,
+        element: &super::FlexHlsMasterSink,
+        location: &str,
+    ) -> Result<gio::OutputStream, String> {
+        FsStorage.open_playlist(location).map_err(|err| {
+            gst_error!(
+                CAT,
+                obj: element,
+                "Could not open file {} for writing: {}",
+                location,
+                err
+            );
+            err.to_string()
+        })
+    }
+
+    /// Creates the flexhlssink writing the stream of the pad `pad_name`, returning it along with
+    /// the ghost pad feeding its `child_pad_name` pad.
+    fn add_child_sink(
+        &self,
+        element: &super::FlexHlsMasterSink,
+        templ: &gst::PadTemplate,
+        pad_name: &str,
+        child_pad_name: &str,
+        properties: &[(&str, &dyn ToValue)],
+    ) -> Option<(gst::Element, gst::GhostPad)> {
+        // Child elements are named after the pad, so they can be configured through the child
+        // proxy interface, e.g. `video_0::send-keyframe-requests`.
+        let sink = gst::ElementFactory::make("flexhlssink", Some(pad_name)).ok()?;
+        {
+            let settings = self.settings.lock().unwrap();
+            sink.set_properties(&[
+                ("target-duration", &settings.target_duration),
+                ("playlist-length", &settings.playlist_length),
+                ("max-files", &settings.max_num_segment_files),
+            ])
+            .unwrap();
+        }
+        sink.set_properties(properties).unwrap();
+
+        element.add(&sink).ok()?;
+        sink.sync_state_with_parent().ok()?;
+
+        let peer_pad = sink.request_pad_simple(child_pad_name)?;
+        let sink_pad =
+            gst::GhostPad::from_template_with_target(templ, Some(pad_name), &peer_pad).ok()?;
+
+        let this = self.clone();
+        let element_weak = element.downgrade();
+        let probe_pad_name = pad_name.to_string();
+        sink_pad
+            .add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_pad, info| {
+                let element = match element_weak.upgrade() {
+                    Some(element) => element,
+                    None => return gst::PadProbeReturn::Ok,
+                };
+
+                let changed = match info.data {
+                    Some(gst::PadProbeData::Event(ref event)) => match event.view() {
+                        gst::EventView::Caps(caps) => {
+                            this.on_stream_caps(&probe_pad_name, caps.caps_owned())
+                        }
+                        gst::EventView::Tag(tag) => this.on_stream_tags(&probe_pad_name, tag.tag()),
+                        _ => false,
+                    },
+                    _ => false,
+                };
+
+                if changed {
+                    this.schedule_master_playlist(&element);
+                }
+                gst::PadProbeReturn::Ok
+            })
+            .unwrap();
+
+        element.add_pad(&sink_pad).ok()?;
+        sink_pad.set_active(true).ok()?;
+
+        Some((sink, sink_pad))
+    }
+
+    fn add_variant(
+        &self,
+        element: &super::FlexHlsMasterSink,
+        templ: &gst::PadTemplate,
+        name: Option<String>,
+    ) -> Option<gst::Pad> {
+        let index = {
+            let mut state = self.state.lock().unwrap();
+            pad_index(name.as_deref(), "video_", &mut state.next_video_index)
+        };
+        let pad_name = format!("video_{}", index);
+
+        let (location, playlist_location, iframe_playlist_location) = {
+            let settings = self.settings.lock().unwrap();
+            let index = index.to_string();
+            (
+                settings.location.replace(PAD_INDEX_PLACEHOLDER, &index),
+                settings
+                    .playlist_location
+                    .replace(PAD_INDEX_PLACEHOLDER, &index),
+                settings
+                    .iframe_playlist_location
+                    .as_ref()
+                    .map(|location| location.replace(PAD_INDEX_PLACEHOLDER, &index)),
+            )
+        };
+
+        let (sink, sink_pad) = self.add_child_sink(
+            element,
+            templ,
+            &pad_name,
+            "video",
+            &[
+                ("location", &location),
+                ("playlist-location", &playlist_location),
+                ("iframe-playlist-location", &iframe_playlist_location),
+            ],
+        )?;
+
+        gst_info!(
+            CAT,
+            obj: element,
+            "Added variant {} writing playlist {}",
+            pad_name,
+            playlist_location
+        );
+
+        let mut state = self.state.lock().unwrap();
+        state.variants.push(Variant {
+            pad_name,
+            sink,
+            playlist_location,
+            iframe_playlist_location,
+            caps: None,
+            bitrate: Bitrate::default(),
+            iframe_bitrate: Bitrate::default(),
+        });
+
+        Some(sink_pad.upcast())
+    }
+
+    fn add_rendition(
+        &self,
+        element: &super::FlexHlsMasterSink,
+        templ: &gst::PadTemplate,
+        name: Option<String>,
+    ) -> Option<gst::Pad> {
+        let index = {
+            let mut state = self.state.lock().unwrap();
+            pad_index(name.as_deref(), "audio_", &mut state.next_audio_index)
+        };
+        let pad_name = format!("audio_{}", index);
+
+        let (location, playlist_location) = {
+            let settings = self.settings.lock().unwrap();
+            let index = index.to_string();
+            (
+                settings
+                    .audio_location
+                    .replace(PAD_INDEX_PLACEHOLDER, &index),
+                settings
+                    .audio_playlist_location
+                    .replace(PAD_INDEX_PLACEHOLDER, &index),
+            )
+        };
+
+        let (sink, sink_pad) = self.add_child_sink(
+            element,
+            templ,
+            &pad_name,
+            "audio_%u",
+            &[
+                ("location", &location),
+                ("playlist-location", &playlist_location),
+            ],
+        )?;
+
+        gst_info!(
+            CAT,
+            obj: element,
+            "Added audio rendition {} writing playlist {}",
+            pad_name,
+            playlist_location
+        );
+
+        let mut state = self.state.lock().unwrap();
+        state.renditions.push(Rendition {
+            pad_name,
+            sink,
+            playlist_location,
+            caps: None,
+            bitrate: Bitrate::default(),
+            language: None,
+            name: None,
+        });
+
+        Some(sink_pad.upcast())
+    }
+}
+
+/// Index of a requested pad, taken from its name when given, e.g. `video_1`.
+fn pad_index(name: Option<&str>, prefix: &str, next_index: &mut u32) -> u32 {
+    let index = name
+        .and_then(|name| name.strip_prefix(prefix))
+        .and_then(|index| index.parse::<u32>().ok())
+        .unwrap_or(*next_index);
+    *next_index = (*next_index).max(index + 1);
+    index
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for FlexHlsMasterSink {
+    const NAME: &'static str = "FlexHlsMasterSink";
+    type Type = super::FlexHlsMasterSink;
+    type ParentType = gst::Bin;
+}
+
+impl BinImpl for FlexHlsMasterSink {
+    fn handle_message(&self, bin: &Self::Type, msg: gst::Message) {
+        if let gst::MessageView::Element(ref element_msg) = msg.view() {
+            let changed = match (msg.src(), element_msg.structure()) {
+                (Some(src), Some(s)) if s.name() == "hls-segment-added" => {
+                    self.on_segment_added(&src, s)
+                }
+                _ => false,
+            };
+            if changed {
+                self.schedule_master_playlist(bin);
+            }
+        }
+        self.parent_handle_message(bin, msg)
+    }
+}
+
+impl ObjectImpl for FlexHlsMasterSink {
+    fn properties() -> &'static [glib::ParamSpec] {
+        static PROPERTIES: Lazy<Vec<glib::ParamSpec>> = Lazy::new(|| {
+            vec![
+                glib::ParamSpec::new_string(
+                    "location",
+                    "File Location",
+                    "Location of the segment files to write, %u is replaced by the variant index",
+                    Some(DEFAULT_LOCATION),
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_string(
+                    "playlist-location",
+                    "Playlist Location",
+                    "Location of the variant playlists to write, %u is replaced by the variant index",
+                    Some(DEFAULT_PLAYLIST_LOCATION),
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_string(
+                    "master-playlist-location",
+                    "Master Playlist Location",
+                    "Location of the master playlist to write.",
+                    Some(DEFAULT_MASTER_PLAYLIST_LOCATION),
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_string(
+                    "iframe-playlist-location",
+                    "I-frame Playlist Location",
+                    "Location of the I-frame only playlists to write, %u is replaced by the variant index. (NULL - disabled)",
+                    None,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_string(
+                    "audio-location",
+                    "Audio File Location",
+                    "Location of the audio segment files to write, %u is replaced by the rendition index",
+                    Some(DEFAULT_AUDIO_LOCATION),
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_string(
+                    "audio-playlist-location",
+                    "Audio Playlist Location",
+                    "Location of the audio rendition playlists to write, %u is replaced by the rendition index",
+                    Some(DEFAULT_AUDIO_PLAYLIST_LOCATION),
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_uint(
+                    "max-files",
+                    "Max files",
+                    "Maximum number of files of each variant to keep on disk. Once the maximum is reached, old files start to be deleted to make room for new ones.",
+                    0,
+                    u32::MAX,
+                    DEFAULT_MAX_NUM_SEGMENT_FILES,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_uint(
+                    "target-duration",
+                    "Target duration",
+                    "The target duration in seconds of a segment/file of each variant.",
+                    0,
+                    u32::MAX,
+                    DEFAULT_TARGET_DURATION,
+                    glib::ParamFlags::READWRITE,
+                ),
+                glib::ParamSpec::new_uint(
+                    "playlist-length",
+                    "Playlist length",
+                    "Length of each variant HLS playlist. If set to 0, the playlist will be infinite.",
+                    0,
+                    u32::MAX,
+                    DEFAULT_PLAYLIST_LENGTH,
+                    glib::ParamFlags::READWRITE,
+                ),
+            ]
+        });
+
+        PROPERTIES.as_ref()
+    }
+
+    fn signals() -> &'static [glib::subclass::Signal] {
+        static SIGNALS: Lazy<Vec<glib::subclass::Signal>> = Lazy::new(|| {
+            vec![glib::subclass::Signal::builder(
+                SIGNAL_GET_MASTER_PLAYLIST_STREAM,
+                &[String::static_type().into()],
+                gio::OutputStream::static_type().into(),
+            )
+            .action()
+            .class_handler(|_, args| {
+                let element = args[0]
+                    .get::<super::FlexHlsMasterSink>()
+                    .expect("master-playlist-stream signal arg");
+                let playlist_location = args[1]
+                    .get::<String>()
+                    .expect("master-playlist-stream signal arg");
+                let flexhlsmastersink = FlexHlsMasterSink::from_instance(&element);
+
+                Some(
+                    flexhlsmastersink
+                        .new_file_stream(&element, &playlist_location)
+                        .ok()?
+                        .to_value(),
+                )
+            })
+            .build()]
+        });
+
+        SIGNALS.as_ref()
+    }
+
+    fn set_property(
+        &self,
+        _obj: &Self::Type,
+        _id: usize,
+        value: &glib::Value,
+        pspec: &glib::ParamSpec,
+    ) {
+        let mut settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "location" => {
+                settings.location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_LOCATION.into());
+            }
+            "playlist-location" => {
+                settings.playlist_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_PLAYLIST_LOCATION.into());
+            }
+            "master-playlist-location" => {
+                settings.master_playlist_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_MASTER_PLAYLIST_LOCATION.into());
+            }
+            "iframe-playlist-location" => {
+                settings.iframe_playlist_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream");
+            }
+            "audio-location" => {
+                settings.audio_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_AUDIO_LOCATION.into());
+            }
+            "audio-playlist-location" => {
+                settings.audio_playlist_location = value
+                    .get::<Option<String>>()
+                    .expect("type checked upstream")
+                    .unwrap_or_else(|| DEFAULT_AUDIO_PLAYLIST_LOCATION.into());
+            }
+            "max-files" => {
+                settings.max_num_segment_files = value.get().expect("type checked upstream");
+            }
+            "target-duration" => {
+                settings.target_duration = value.get().expect("type checked upstream");
+            }
+            "playlist-length" => {
+                settings.playlist_length = value.get().expect("type checked upstream");
+            }
+            _ => unimplemented!(),
+        };
+
+        // Keep the variants created so far in sync with the shared settings.
+        if matches!(
+            pspec.name(),
+            "max-files" | "target-duration" | "playlist-length"
+        ) {
+            let state = self.state.lock().unwrap();
+            let sinks = state
+                .variants
+                .iter()
+                .map(|variant| &variant.sink)
+                .chain(state.renditions.iter().map(|rendition| &rendition.sink));
+            for sink in sinks {
+                sink.set_property(pspec.name(), value).unwrap();
+            }
+        }
+    }
+
+    fn property(&self, _obj: &Self::Type, _id: usize, pspec: &glib::ParamSpec) -> glib::Value {
+        let settings = self.settings.lock().unwrap();
+        match pspec.name() {
+            "location" => settings.location.to_value(),
+            "playlist-location" => settings.playlist_location.to_value(),
+            "master-playlist-location" => settings.master_playlist_location.to_value(),
+            "iframe-playlist-location" => settings.iframe_playlist_location.to_value(),
+            "audio-location" => settings.audio_location.to_value(),
+            "audio-playlist-location" => settings.audio_playlist_location.to_value(),
+            "max-files" => settings.max_num_segment_files.to_value(),
+            "target-duration" => settings.target_duration.to_value(),
+            "playlist-length" => settings.playlist_length.to_value(),
+            _ => unimplemented!(),
+        }
+    }
+
+    fn constructed(&self, obj: &Self::Type) {
+        self.parent_constructed(obj);
+
+        obj.set_element_flags(gst::ElementFlags::SINK);
+    }
+}
+
+impl ElementImpl for FlexHlsMasterSink {
+    fn metadata() -> Option<&'static gst::subclass::ElementMetadata> {
+        static ELEMENT_METADATA: Lazy<gst::subclass::ElementMetadata> = Lazy::new(|| {
+            gst::subclass::ElementMetadata::new(
+                "Flexible HTTP Live Streaming multivariant sink",
+                "Sink/Muxer",
+                "Writes a playlist for each video variant and audio rendition, and a master playlist referencing them",
+                "Rafael Caricio <rafael@caricio.com>",
+            )
+        });
+
+        Some(&*ELEMENT_METADATA)
+    }
+
+    fn pad_templates() -> &'static [gst::PadTemplate] {
+        static PAD_TEMPLATES: Lazy<Vec<gst::PadTemplate>> = Lazy::new(|| {
+            let caps = gst::Caps::new_any();
+            let video_pad_template = gst::PadTemplate::new(
+                "video_%u",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Request,
+                &caps,
+            )
+            .unwrap();
+
+            let caps = gst::Caps::new_any();
+            let audio_pad_template = gst::PadTemplate::new(
+                "audio_%u",
+                gst::PadDirection::Sink,
+                gst::PadPresence::Request,
+                &caps,
+            )
+            .unwrap();
+
+            vec![video_pad_template, audio_pad_template]
+        });
+
+        PAD_TEMPLATES.as_ref()
+    }
+
+    fn request_new_pad(
+        &self,
+        element: &Self::Type,
+        templ: &gst::PadTemplate,
+        name: Option<String>,
+        _caps: Option<&gst::Caps>,
+    ) -> Option<gst::Pad> {
+        match templ.name_template().as_ref().map(|val| val.as_str()) {
+            Some("video_%u") => self.add_variant(element, templ, name),
+            Some("audio_%u") => self.add_rendition(element, templ, name),
+            other_name => {
+                gst_debug!(
+                    CAT,
+                    obj: element,
+                    "requested_new_pad: name {:?} is not a video variant or audio rendition",
+                    other_name
+                );
+                None
+            }
+        }
+    }
+
+    fn release_pad(&self, element: &Self::Type, pad: &gst::Pad) {
+        let sink = {
+            let mut state = self.state.lock().unwrap();
+            let pad_name = pad.name();
+            let variant_idx = state
+                .variants
+                .iter()
+                .position(|variant| variant.pad_name == pad_name.as_str());
+            let rendition_idx = state
+                .renditions
+                .iter()
+                .position(|rendition| rendition.pad_name == pad_name.as_str());
+            match (variant_idx, rendition_idx) {
+                (Some(idx), _) => state.variants.remove(idx).sink,
+                (None, Some(idx)) => state.renditions.remove(idx).sink,
+                (None, None) => return,
+            }
+        };
+
+        pad.set_active(false).unwrap();
+        element.remove_pad(pad).unwrap();
+
+        let _ = sink.set_state(gst::State::Null);
+        element.remove(&sink).unwrap();
+
+        self.schedule_master_playlist(element);
+    }
+
+    fn change_state(
+        &self,
+        element: &Self::Type,
+        transition: gst::StateChange,
+    ) -> Result<gst::StateChangeSuccess, gst::StateChangeError> {
+        let ret = self.parent_change_state(element, transition)?;
+
+        // The last segments of the variants were written, so is the master playlist.
+        if let gst::StateChange::PausedToReady = transition {
+            self.stop_writer();
+        }
+
+        Ok(ret)
+    }
+}
@@ -0,0 +1,8 @@
+mod imp;
+
+glib::wrapper! {
+    pub struct FlexHlsMasterSink(ObjectSubclass<imp::FlexHlsMasterSink>) @extends gst::Bin, gst::Element, gst::Object;
+}
+
+unsafe impl Send for FlexHlsMasterSink {}
+unsafe impl Sync for FlexHlsMasterSink {}
@@ -1,11 +1,397 @@
-use m3u8_rs::playlist;
+use m3u8_rs::playlist::{self, MediaSegment};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
 
-pub struct MediaPlaylist(playlist::MediaPlaylist);
+/// Parts are only kept for the segments in the last three target durations of the playlist, as
+/// recommended by the Low-Latency HLS specification.
+const PART_RETENTION_TARGET_DURATIONS: f32 = 3.0;
 
-impl MediaPlaylist {}
+/// Byte ranges in I-frame playlists require at least version 4.
+const IFRAME_PLAYLIST_VERSION: usize = 4;
+
+/// `EXT-X-GAP` requires at least version 8.
+const GAP_VERSION: usize = 8;
+
+/// A partial segment (`EXT-X-PART`) addressed as a byte range of its parent segment.
+#[derive(Clone, Debug)]
+pub struct PartialSegment {
+    pub uri: String,
+    pub duration: f32,
+    pub offset: u64,
+    pub length: u64,
+    pub independent: bool,
+}
+
+impl PartialSegment {
+    fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        write!(
+            w,
+            "#EXT-X-PART:DURATION={:.3},URI=\"{}\",BYTERANGE=\"{}@{}\"",
+            self.duration, self.uri, self.length, self.offset
+        )?;
+        if self.independent {
+            write!(w, ",INDEPENDENT=YES")?;
+        }
+        writeln!(w)
+    }
+}
+
+/// Byte range of a segment in its file (`EXT-X-BYTERANGE`). Unlike the one of `m3u8-rs`, it is
+/// not limited to the first 2 GiB of the file.
+#[derive(Clone, Copy, Debug)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl ByteRange {
+    fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        writeln!(w, "#EXT-X-BYTERANGE:{}@{}", self.length, self.offset)
+    }
+}
+
+/// A keyframe addressed as a byte range of its segment, listed in the I-frame playlist.
+#[derive(Clone, Debug)]
+pub struct IFrame {
+    pub offset: u64,
+    pub length: u64,
+    /// Time until the next keyframe, in seconds.
+    pub duration: f32,
+}
+
+/// Ad break marker written before a segment, for players not supporting `EXT-X-DATERANGE`.
+#[derive(Clone, Debug)]
+pub enum CueTag {
+    /// Start of an ad break (`EXT-X-CUE-OUT`), with its duration in seconds when known.
+    Out(Option<f32>),
+    /// End of an ad break (`EXT-X-CUE-IN`).
+    In,
+}
+
+impl CueTag {
+    fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        match self {
+            CueTag::Out(Some(duration)) => writeln!(w, "#EXT-X-CUE-OUT:DURATION={:.3}", duration),
+            CueTag::Out(None) => writeln!(w, "#EXT-X-CUE-OUT"),
+            CueTag::In => writeln!(w, "#EXT-X-CUE-IN"),
+        }
+    }
+}
+
+/// Where the next partial segment is going to be written (`EXT-X-PRELOAD-HINT`).
+#[derive(Clone, Debug)]
+pub struct PreloadHint {
+    pub uri: String,
+    pub offset: u64,
+}
+
+/// Media playlist with support for the tags `m3u8-rs` does not know how to render.
+pub struct MediaPlaylist {
+    inner: playlist::MediaPlaylist,
+    /// Media sequence number of each segment of the playlist, in the same order.
+    segment_sequences: VecDeque<u64>,
+    /// Target duration of the partial segments, Low-Latency HLS is disabled when `None`.
+    part_target: Option<f32>,
+    /// Partial segments of each segment of the playlist, in the same order.
+    segment_parts: VecDeque<Vec<PartialSegment>>,
+    /// Partial segments of the segment currently being written.
+    pending_parts: Vec<PartialSegment>,
+    preload_hint: Option<PreloadHint>,
+    /// Keyframes of each segment of the playlist, in the same order.
+    segment_iframes: VecDeque<Vec<IFrame>>,
+    /// Media sequence number of the first keyframe in the I-frame playlist.
+    iframe_sequence: i32,
+    /// Ad break marker of each segment of the playlist, in the same order.
+    segment_cues: VecDeque<Option<CueTag>>,
+    /// Whether each segment of the playlist is missing (`EXT-X-GAP`), in the same order.
+    segment_gaps: VecDeque<bool>,
+    /// Byte range of each segment of the playlist in its file, in the same order.
+    segment_byte_ranges: VecDeque<Option<ByteRange>>,
+}
+
+impl MediaPlaylist {
+    pub fn new(inner: playlist::MediaPlaylist, part_target: Option<f32>) -> Self {
+        Self {
+            inner,
+            segment_sequences: VecDeque::new(),
+            part_target,
+            segment_parts: VecDeque::new(),
+            pending_parts: vec![],
+            preload_hint: None,
+            segment_iframes: VecDeque::new(),
+            iframe_sequence: 0,
+            segment_cues: VecDeque::new(),
+            segment_gaps: VecDeque::new(),
+            segment_byte_ranges: VecDeque::new(),
+        }
+    }
+
+    /// Appends the segment numbered `sequence`, the playlist starting at the sequence number of
+    /// its first segment (`EXT-X-MEDIA-SEQUENCE`).
+    pub fn push_segment(
+        &mut self,
+        segment: MediaSegment,
+        sequence: u64,
+        parts: Vec<PartialSegment>,
+        iframes: Vec<IFrame>,
+        cue: Option<CueTag>,
+    ) {
+        if self.inner.segments.is_empty() {
+            self.inner.media_sequence = sequence as i32;
+        }
+        self.inner.segments.push(segment);
+        self.segment_sequences.push_back(sequence);
+        self.segment_parts.push_back(parts);
+        self.segment_iframes.push_back(iframes);
+        self.segment_cues.push_back(cue);
+        self.segment_gaps.push_back(false);
+        self.segment_byte_ranges.push_back(None);
+        self.trim_parts();
+    }
+
+    /// Addresses the last segment as a byte range of its file.
+    pub fn set_last_segment_byte_range(&mut self, byte_range: ByteRange) {
+        if let Some(last) = self.segment_byte_ranges.back_mut() {
+            *last = Some(byte_range);
+        }
+    }
+
+    /// Marks the last segment as missing, e.g. when its data could not be written.
+    pub fn mark_last_segment_gap(&mut self) {
+        if let Some(gap) = self.segment_gaps.back_mut() {
+            *gap = true;
+            self.inner.version = self.inner.version.max(GAP_VERSION);
+        }
+    }
+
+    pub fn remove_first_segment(&mut self) -> Option<MediaSegment> {
+        if self.inner.segments.is_empty() {
+            return None;
+        }
+        if let Some(sequence) = self.segment_sequences.pop_front() {
+            self.inner.media_sequence = match self.segment_sequences.front() {
+                Some(next_sequence) => *next_sequence as i32,
+                None => sequence as i32 + 1,
+            };
+        }
+        self.segment_parts.pop_front();
+        self.segment_cues.pop_front();
+        self.segment_gaps.pop_front();
+        self.segment_byte_ranges.pop_front();
+        if let Some(iframes) = self.segment_iframes.pop_front() {
+            self.iframe_sequence += iframes.len() as i32;
+        }
+        let segment = self.inner.segments.remove(0);
+        // Counts the discontinuities no longer in the playlist (`EXT-X-DISCONTINUITY-SEQUENCE`).
+        if segment.discontinuity {
+            self.inner.discontinuity_sequence += 1;
+        }
+        Some(segment)
+    }
+
+    pub fn push_part(&mut self, part: PartialSegment) {
+        self.pending_parts.push(part);
+    }
+
+    pub fn take_pending_parts(&mut self) -> Vec<PartialSegment> {
+        std::mem::take(&mut self.pending_parts)
+    }
+
+    pub fn set_preload_hint(&mut self, preload_hint: Option<PreloadHint>) {
+        self.preload_hint = preload_hint;
+    }
+
+    fn trim_parts(&mut self) {
+        if self.part_target.is_none() {
+            return;
+        }
+
+        let retention = self.inner.target_duration * PART_RETENTION_TARGET_DURATIONS;
+        let mut duration = self
+            .pending_parts
+            .iter()
+            .map(|part| part.duration)
+            .sum::<f32>();
+        for (segment, parts) in self
+            .inner
+            .segments
+            .iter()
+            .rev()
+            .zip(self.segment_parts.iter_mut().rev())
+        {
+            if duration > retention {
+                parts.clear();
+            }
+            duration += segment.duration;
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        // `m3u8-rs` renders the playlist header, the segments are rendered here so the tags it
+        // does not support can be interleaved with them.
+        let header = playlist::MediaPlaylist {
+            version: self.inner.version,
+            target_duration: self.inner.target_duration,
+            media_sequence: self.inner.media_sequence,
+            segments: vec![],
+            discontinuity_sequence: self.inner.discontinuity_sequence,
+            end_list: false,
+            playlist_type: self.inner.playlist_type.clone(),
+            i_frames_only: self.inner.i_frames_only,
+            start: self.inner.start.clone(),
+            independent_segments: self.inner.independent_segments,
+            unknown_tags: self.inner.unknown_tags.clone(),
+        };
+        header.write_to(w)?;
+
+        if let Some(part_target) = self.part_target {
+            writeln!(w, "#EXT-X-PART-INF:PART-TARGET={:.3}", part_target)?;
+            // Low-Latency HLS requires blocking playlist reloads, the origin serving the
+            // playlist has to answer the `_HLS_msn` and `_HLS_part` query parameters.
+            writeln!(
+                w,
+                "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+                part_target * 3.0
+            )?;
+        }
+
+        for (idx, segment) in self.inner.segments.iter().enumerate() {
+            if let Some(Some(cue)) = self.segment_cues.get(idx) {
+                cue.write_to(w)?;
+            }
+            if let Some(true) = self.segment_gaps.get(idx) {
+                writeln!(w, "#EXT-X-GAP")?;
+            }
+            if self.part_target.is_some() {
+                for part in self.segment_parts.get(idx).into_iter().flatten() {
+                    part.write_to(w)?;
+                }
+            }
+            if let Some(Some(byte_range)) = self.segment_byte_ranges.get(idx) {
+                byte_range.write_to(w)?;
+            }
+            segment.write_to(w)?;
+        }
+
+        if self.part_target.is_some() && !self.inner.end_list {
+            for part in &self.pending_parts {
+                part.write_to(w)?;
+            }
+            if let Some(hint) = &self.preload_hint {
+                writeln!(
+                    w,
+                    "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}\",BYTERANGE-START={}",
+                    hint.uri, hint.offset
+                )?;
+            }
+        }
+
+        if self.inner.end_list {
+            writeln!(w, "#EXT-X-ENDLIST")?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the I-frame playlist (`EXT-X-I-FRAMES-ONLY`) of the segments of the playlist.
+    pub fn write_iframes_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        let header = playlist::MediaPlaylist {
+            version: self.inner.version.max(IFRAME_PLAYLIST_VERSION),
+            target_duration: self.inner.target_duration,
+            media_sequence: self.iframe_sequence,
+            segments: vec![],
+            discontinuity_sequence: self.inner.discontinuity_sequence,
+            end_list: false,
+            playlist_type: self.inner.playlist_type.clone(),
+            i_frames_only: true,
+            start: None,
+            independent_segments: self.inner.independent_segments,
+            unknown_tags: vec![],
+        };
+        header.write_to(w)?;
+
+        for (segment, iframes) in self.inner.segments.iter().zip(self.segment_iframes.iter()) {
+            for (idx, iframe) in iframes.iter().enumerate() {
+                ByteRange {
+                    offset: iframe.offset,
+                    length: iframe.length,
+                }
+                .write_to(w)?;
+                MediaSegment {
+                    uri: segment.uri.clone(),
+                    duration: iframe.duration,
+                    title: None,
+                    byte_range: None,
+                    discontinuity: idx == 0 && segment.discontinuity,
+                    key: segment.key.clone(),
+                    map: segment.map.clone(),
+                    program_date_time: None,
+                    daterange: None,
+                }
+                .write_to(w)?;
+            }
+        }
+
+        if self.inner.end_list {
+            writeln!(w, "#EXT-X-ENDLIST")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Deref for MediaPlaylist {
+    type Target = playlist::MediaPlaylist;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for MediaPlaylist {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum PlaylistRenderState {
     Init,
     Started,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gap_raises_version() {
+        let mut playlist = MediaPlaylist::new(
+            playlist::MediaPlaylist {
+                version: 3,
+                target_duration: 2.0,
+                ..Default::default()
+            },
+            None,
+        );
+        playlist.push_segment(
+            MediaSegment {
+                uri: "segment00000.ts".to_string(),
+                duration: 2.0,
+                ..Default::default()
+            },
+            0,
+            vec![],
+            vec![],
+            None,
+        );
+        playlist.mark_last_segment_gap();
+
+        let mut content = vec![];
+        playlist.write_to(&mut content).unwrap();
+        let content = String::from_utf8(content).unwrap();
+        assert!(content.contains("#EXT-X-VERSION:8\n"));
+        assert!(content.contains("#EXT-X-GAP\n#EXTINF:2"));
+    }
+}
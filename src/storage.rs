@@ -0,0 +1,238 @@
+use glib::Cast;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const TEMPORARY_PLAYLIST_SUFFIX: &str = ".tmp";
+
+/// Where the segments and playlists are stored, in place of the `get-fragment-stream`,
+/// `get-playlist-stream`, `commit-playlist-stream` and `delete-fragment` signals.
+///
+/// Locations are the ones configured on the element, e.g. `segment00001.ts`.
+pub trait HlsStorage: Send + Sync {
+    /// Opens the stream a segment, init segment or key is written to, replacing any previous
+    /// content at `location`.
+    fn open_segment(&self, location: &str) -> io::Result<gio::OutputStream>;
+
+    /// Opens the stream a new version of a playlist is written to. The playlist is only
+    /// replaced once committed, so readers never see a partially written playlist.
+    fn open_playlist(&self, location: &str) -> io::Result<gio::OutputStream>;
+
+    /// Replaces the playlist with the version written since `open_playlist`.
+    fn commit_playlist(&self, location: &str) -> io::Result<()>;
+
+    fn delete(&self, location: &str) -> io::Result<()>;
+
+    /// Locations of the stored files starting with `prefix`.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Reads back a stored file, e.g. the playlist to resume.
+    fn read(&self, location: &str) -> io::Result<Vec<u8>>;
+}
+
+/// Storage writing to the local filesystem, locations being file paths.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FsStorage;
+
+impl FsStorage {
+    /// Playlists are written to a sibling temporary file, which replaces the playlist once
+    /// committed.
+    fn temporary_playlist_location(location: &str) -> String {
+        format!("{}{}", location, TEMPORARY_PLAYLIST_SUFFIX)
+    }
+}
+
+impl HlsStorage for FsStorage {
+    fn open_segment(&self, location: &str) -> io::Result<gio::OutputStream> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(location)?;
+        Ok(gio::WriteOutputStream::new(file).upcast())
+    }
+
+    fn open_playlist(&self, location: &str) -> io::Result<gio::OutputStream> {
+        self.open_segment(&Self::temporary_playlist_location(location))
+    }
+
+    fn commit_playlist(&self, location: &str) -> io::Result<()> {
+        fs::rename(Self::temporary_playlist_location(location), location)
+    }
+
+    fn delete(&self, location: &str) -> io::Result<()> {
+        fs::remove_file(location)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let path = Path::new(prefix);
+        let (directory, file_prefix) = match prefix.ends_with('/') {
+            true => (path, ""),
+            false => (
+                path.parent().unwrap_or_else(|| Path::new("")),
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or(""),
+            ),
+        };
+        let directory = match directory.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => directory,
+        };
+
+        let mut locations = vec![];
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            match name.to_str() {
+                Some(name) if name.starts_with(file_prefix) && entry.file_type()?.is_file() => {
+                    let location = match prefix.rfind('/') {
+                        Some(idx) => format!("{}{}", &prefix[..=idx], name),
+                        None => name.to_string(),
+                    };
+                    locations.push(location);
+                }
+                _ => (),
+            }
+        }
+        locations.sort();
+        Ok(locations)
+    }
+
+    fn read(&self, location: &str) -> io::Result<Vec<u8>> {
+        fs::read(location)
+    }
+}
+
+type MemoryFile = Arc<Mutex<Vec<u8>>>;
+
+#[derive(Default)]
+struct MemoryFiles {
+    files: BTreeMap<String, MemoryFile>,
+    /// Playlists written but not committed yet.
+    pending_playlists: BTreeMap<String, MemoryFile>,
+}
+
+/// Storage keeping everything in memory, e.g. to test the playlists without touching the disk.
+///
+/// Clones share the same files.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<MemoryFiles>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Content of the file at `location`, `None` when there is none.
+    pub fn get(&self, location: &str) -> Option<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        let file = files.files.get(location)?;
+        let content = file.lock().unwrap().clone();
+        Some(content)
+    }
+}
+
+/// Writes to a file of a `MemoryStorage`.
+struct MemoryWriter(MemoryFile);
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn not_found(location: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("No file at {}", location))
+}
+
+impl HlsStorage for MemoryStorage {
+    fn open_segment(&self, location: &str) -> io::Result<gio::OutputStream> {
+        let file = MemoryFile::default();
+        self.files
+            .lock()
+            .unwrap()
+            .files
+            .insert(location.to_string(), file.clone());
+        Ok(gio::WriteOutputStream::new(MemoryWriter(file)).upcast())
+    }
+
+    fn open_playlist(&self, location: &str) -> io::Result<gio::OutputStream> {
+        let file = MemoryFile::default();
+        self.files
+            .lock()
+            .unwrap()
+            .pending_playlists
+            .insert(location.to_string(), file.clone());
+        Ok(gio::WriteOutputStream::new(MemoryWriter(file)).upcast())
+    }
+
+    fn commit_playlist(&self, location: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let file = files
+            .pending_playlists
+            .remove(location)
+            .ok_or_else(|| not_found(location))?;
+        files.files.insert(location.to_string(), file);
+        Ok(())
+    }
+
+    fn delete(&self, location: &str) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .files
+            .remove(location)
+            .map(|_| ())
+            .ok_or_else(|| not_found(location))
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .files
+            .lock()
+            .unwrap()
+            .files
+            .keys()
+            .filter(|location| location.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn read(&self, location: &str) -> io::Result<Vec<u8>> {
+        self.get(location).ok_or_else(|| not_found(location))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gio::prelude::*;
+
+    #[test]
+    fn memory_playlist_replaced_on_commit() {
+        let storage = MemoryStorage::new();
+        let stream = storage.open_playlist("playlist.m3u8").unwrap();
+        stream
+            .write_all(b"#EXTM3U\n", gio::NONE_CANCELLABLE)
+            .unwrap();
+        stream.close(gio::NONE_CANCELLABLE).unwrap();
+        assert!(storage.get("playlist.m3u8").is_none());
+
+        storage.commit_playlist("playlist.m3u8").unwrap();
+        assert_eq!(storage.read("playlist.m3u8").unwrap(), b"#EXTM3U\n");
+        assert_eq!(storage.list("play").unwrap(), vec!["playlist.m3u8"]);
+
+        storage.delete("playlist.m3u8").unwrap();
+        assert!(storage.list("").unwrap().is_empty());
+    }
+}
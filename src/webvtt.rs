@@ -0,0 +1,82 @@
+use std::io::Write;
+
+/// A subtitle cue, timed in running time.
+#[derive(Clone, Debug)]
+pub struct Cue {
+    pub start: u64,
+    pub end: u64,
+    pub text: String,
+}
+
+impl Cue {
+    /// Makes a cue from the payload of a `text/x-raw` or `application/x-subtitle-vtt` buffer.
+    pub fn from_payload(start: u64, end: u64, payload: &[u8]) -> Option<Self> {
+        let payload = std::str::from_utf8(payload).ok()?;
+        // WebVTT buffers may carry the cue timings, these are replaced by the buffer timestamps.
+        let text = payload
+            .lines()
+            .filter(|line| !line.contains("-->"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let text = text.trim();
+        if text.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            start,
+            end,
+            text: text.to_string(),
+        })
+    }
+}
+
+/// Formats a time in nanoseconds as a WebVTT timestamp, e.g. `01:02:03.456`.
+fn timestamp(time: u64) -> String {
+    let millis = time / gst::MSECOND_VAL;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1_000 % 60,
+        millis % 1_000
+    )
+}
+
+/// Writes a WebVTT segment with the cues overlapping it.
+///
+/// Cue times are running times, `mpegts` is the MPEG-TS timestamp (90kHz) of the running time 0
+/// in the media segments, used for `X-TIMESTAMP-MAP` so players can synchronize the cues.
+pub fn write_segment<W: Write>(w: &mut W, cues: &[Cue], mpegts: u64) -> std::io::Result<()> {
+    writeln!(w, "WEBVTT")?;
+    writeln!(w, "X-TIMESTAMP-MAP=MPEGTS:{},LOCAL:00:00:00.000", mpegts)?;
+    for cue in cues {
+        writeln!(w)?;
+        writeln!(w, "{} --> {}", timestamp(cue.start), timestamp(cue.end))?;
+        writeln!(w, "{}", cue.text)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_with_cue() {
+        let cue = Cue::from_payload(
+            3_723_456 * gst::MSECOND_VAL,
+            3_725_000 * gst::MSECOND_VAL,
+            b"00:00.000 --> 00:02.000\nHello\n",
+        )
+        .unwrap();
+
+        let mut segment = vec![];
+        write_segment(&mut segment, &[cue], 0).unwrap();
+        assert_eq!(
+            String::from_utf8(segment).unwrap(),
+            "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:0,LOCAL:00:00:00.000\n\n\
+            01:02:03.456 --> 01:02:05.000\nHello\n"
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use crate::imp::WriteQueueFull;
+use crate::storage::HlsStorage;
+use gio::prelude::*;
+use gst::gst_warning;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Segment data is queued in chunks of up to this size.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
+    gst::DebugCategory::new(
+        "flexhlssink-writer",
+        gst::DebugColorFlags::empty(),
+        Some("Flexible HLS sink writer thread"),
+    )
+});
+
+/// Work done by the writer thread, in order.
+enum Job {
+    Open {
+        id: u64,
+        location: String,
+        playlist: bool,
+    },
+    Write {
+        id: u64,
+        data: Vec<u8>,
+    },
+    Close {
+        id: u64,
+    },
+    /// Closes a segment whose data was dropped, and deletes what was written of it.
+    Discard {
+        id: u64,
+    },
+    Commit {
+        location: String,
+    },
+    Delete {
+        location: String,
+    },
+}
+
+fn writer_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "The writer thread is stopped")
+}
+
+fn queue_full() -> io::Error {
+    io::Error::new(io::ErrorKind::WouldBlock, "The write queue is full")
+}
+
+/// Queues a job, waiting for room in the queue only when `block` is set.
+fn send(sender: &mpsc::SyncSender<Job>, job: Job, block: bool) -> io::Result<()> {
+    if block {
+        return sender.send(job).map_err(|_| writer_stopped());
+    }
+    sender.try_send(job).map_err(|err| match err {
+        mpsc::TrySendError::Full(_) => queue_full(),
+        mpsc::TrySendError::Disconnected(_) => writer_stopped(),
+    })
+}
+
+/// Runs the queued jobs on the streams opened from `storage`, keeping the last failure.
+fn write_loop(
+    storage: Arc<dyn HlsStorage>,
+    receiver: mpsc::Receiver<Job>,
+    failure: Arc<Mutex<Option<String>>>,
+) {
+    // Streams failing to open are kept as `None`, so their data is skipped.
+    let mut streams: HashMap<u64, (String, Option<gio::OutputStream>)> = HashMap::new();
+
+    for job in receiver {
+        let result = match job {
+            Job::Open {
+                id,
+                location,
+                playlist,
+            } => {
+                let stream = match playlist {
+                    true => storage.open_playlist(&location),
+                    false => storage.open_segment(&location),
+                };
+                match stream {
+                    Ok(stream) => {
+                        streams.insert(id, (location, Some(stream)));
+                        Ok(())
+                    }
+                    Err(err) => {
+                        let err = format!("Could not open file {} for writing: {}", location, err);
+                        streams.insert(id, (location, None));
+                        Err(err)
+                    }
+                }
+            }
+            Job::Write { id, data } => match streams.get(&id) {
+                Some((location, Some(stream))) => stream
+                    .write_all(&data, gio::NONE_CANCELLABLE)
+                    .map(|_| ())
+                    .map_err(|err| format!("Could not write {}: {}", location, err)),
+                _ => Ok(()),
+            },
+            Job::Close { id } => match streams.remove(&id) {
+                Some((location, Some(stream))) => stream
+                    .close(gio::NONE_CANCELLABLE)
+                    .map_err(|err| format!("Could not close {}: {}", location, err)),
+                _ => Ok(()),
+            },
+            Job::Discard { id } => {
+                if let Some((location, stream)) = streams.remove(&id) {
+                    if let Some(stream) = stream {
+                        let _ = stream.close(gio::NONE_CANCELLABLE);
+                    }
+                    if let Err(err) = storage.delete(&location) {
+                        gst_warning!(CAT, "Could not delete dropped {}: {}", location, err);
+                    }
+                }
+                Ok(())
+            }
+            Job::Commit { location } => storage
+                .commit_playlist(&location)
+                .map_err(|err| format!("Could not replace playlist {}: {}", location, err)),
+            // Like the synchronous deletions, failing to delete an old segment is not fatal.
+            Job::Delete { location } => {
+                if let Err(err) = storage.delete(&location) {
+                    gst_warning!(CAT, "Could not delete {}: {}", location, err);
+                }
+                Ok(())
+            }
+        };
+
+        if let Err(err) = result {
+            gst_warning!(CAT, "{}", err);
+            *failure.lock().unwrap() = Some(err);
+        }
+    }
+}
+
+/// Writes to a stream opened on the writer thread, queuing the data in chunks.
+struct QueueWriter {
+    id: u64,
+    location: String,
+    buffer: Vec<u8>,
+    sender: mpsc::SyncSender<Job>,
+    policy: WriteQueueFull,
+    /// Whether the data can be dropped when the queue is full, as for media segments.
+    droppable: bool,
+    /// Set once data was dropped, the rest of the segment is then dropped too.
+    dropped: bool,
+    dropped_locations: Arc<Mutex<HashSet<String>>>,
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl QueueWriter {
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if self.dropped || self.buffer.is_empty() {
+            self.buffer.clear();
+            return Ok(());
+        }
+
+        let job = Job::Write {
+            id: self.id,
+            data: std::mem::take(&mut self.buffer),
+        };
+        match (self.policy, self.droppable) {
+            (WriteQueueFull::Block, _) | (WriteQueueFull::DropSegment, false) => {
+                send(&self.sender, job, true)
+            }
+            (WriteQueueFull::Error, _) => send(&self.sender, job, false),
+            (WriteQueueFull::DropSegment, true) => match send(&self.sender, job, false) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    gst_warning!(CAT, "Write queue full, dropping {}", self.location);
+                    self.dropped = true;
+                    self.dropped_locations
+                        .lock()
+                        .unwrap()
+                        .insert(self.location.clone());
+                    Ok(())
+                }
+                res => res,
+            },
+        }
+    }
+}
+
+impl Write for QueueWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.dropped {
+            self.buffer.extend_from_slice(buf);
+        }
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for QueueWriter {
+    /// Sends the rest of the data once the stream is closed, then closes it on the writer thread.
+    fn drop(&mut self) {
+        if let Err(err) = self.send_chunk() {
+            *self.failure.lock().unwrap() = Some(format!(
+                "Could not write the end of {}: {}",
+                self.location, err
+            ));
+        }
+        let job = match self.dropped {
+            true => Job::Discard { id: self.id },
+            false => Job::Close { id: self.id },
+        };
+        let _ = send(&self.sender, job, true);
+    }
+}
+
+/// Moves the writes off the streaming thread, to a thread writing to `storage` in order.
+///
+/// At most `size` jobs (chunks of segment data, playlists, commits and deletions) wait for that
+/// thread. When the queue is full, writing blocks, drops the rest of the current media segment
+/// or fails, depending on the policy. Failures of the writer thread are returned by the next
+/// attempt to open a file.
+pub struct WriteQueue {
+    sender: Mutex<Option<mpsc::SyncSender<Job>>>,
+    writer_thread: Mutex<Option<thread::JoinHandle<()>>>,
+    policy: WriteQueueFull,
+    next_id: AtomicU64,
+    dropped_locations: Arc<Mutex<HashSet<String>>>,
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl WriteQueue {
+    pub fn new(
+        storage: Arc<dyn HlsStorage>,
+        size: usize,
+        policy: WriteQueueFull,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(size);
+        let failure = Arc::new(Mutex::new(None));
+
+        let writer_thread = {
+            let failure = failure.clone();
+            thread::Builder::new()
+                .name("flexhlssink-writer".into())
+                .spawn(move || write_loop(storage, receiver, failure))?
+        };
+
+        Ok(Self {
+            sender: Mutex::new(Some(sender)),
+            writer_thread: Mutex::new(Some(writer_thread)),
+            policy,
+            next_id: AtomicU64::new(0),
+            dropped_locations: Arc::new(Mutex::new(HashSet::new())),
+            failure,
+        })
+    }
+
+    fn sender(&self) -> io::Result<mpsc::SyncSender<Job>> {
+        self.sender
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(writer_stopped)
+    }
+
+    /// Returns the last failure of the writer thread, if any since the previous call.
+    fn check_failure(&self) -> io::Result<()> {
+        match self.failure.lock().unwrap().take() {
+            Some(failure) => Err(io::Error::new(io::ErrorKind::Other, failure)),
+            None => Ok(()),
+        }
+    }
+
+    /// Queues a job other than segment data, which is never dropped.
+    fn queue(&self, job: Job) -> io::Result<()> {
+        let block = self.policy != WriteQueueFull::Error;
+        send(&self.sender()?, job, block)
+    }
+
+    fn open(
+        &self,
+        location: &str,
+        playlist: bool,
+        droppable: bool,
+    ) -> io::Result<gio::OutputStream> {
+        self.check_failure()?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.queue(Job::Open {
+            id,
+            location: location.to_string(),
+            playlist,
+        })?;
+
+        let writer = QueueWriter {
+            id,
+            location: location.to_string(),
+            buffer: vec![],
+            sender: self.sender()?,
+            policy: self.policy,
+            droppable,
+            dropped: false,
+            dropped_locations: self.dropped_locations.clone(),
+            failure: self.failure.clone(),
+        };
+        Ok(gio::WriteOutputStream::new(writer).upcast())
+    }
+
+    /// Opens a stream to a segment, init segment or key. The data of `droppable` segments is
+    /// dropped when the queue is full and the policy allows it.
+    pub fn open_segment(&self, location: &str, droppable: bool) -> io::Result<gio::OutputStream> {
+        self.open(location, false, droppable)
+    }
+
+    pub fn open_playlist(&self, location: &str) -> io::Result<gio::OutputStream> {
+        self.open(location, true, false)
+    }
+
+    pub fn commit_playlist(&self, location: &str) -> io::Result<()> {
+        self.check_failure()?;
+        self.queue(Job::Commit {
+            location: location.to_string(),
+        })
+    }
+
+    pub fn delete(&self, location: &str) -> io::Result<()> {
+        self.queue(Job::Delete {
+            location: location.to_string(),
+        })
+    }
+
+    /// Whether the data of the segment at `location` was dropped, forgetting it.
+    pub fn take_dropped(&self, location: &str) -> bool {
+        self.dropped_locations.lock().unwrap().remove(location)
+    }
+}
+
+impl Drop for WriteQueue {
+    /// Waits for the queued jobs to complete.
+    fn drop(&mut self) {
+        self.sender.lock().unwrap().take();
+        if let Some(writer_thread) = self.writer_thread.lock().unwrap().take() {
+            let _ = writer_thread.join();
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use block_modes::BlockMode;
 use gio::prelude::*;
 use glib::prelude::*;
 use gst::gst_info;
@@ -8,6 +9,8 @@ use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 
+type Aes128Cbc = block_modes::Cbc<aes::Aes128, block_modes::block_padding::Pkcs7>;
+
 static CAT: Lazy<gst::DebugCategory> = Lazy::new(|| {
     gst::DebugCategory::new(
         "flexhlssink-test",
@@ -211,3 +214,1366 @@ fn test_basic_element_properties() {
 
     pipeline.set_state(gst::State::Null).unwrap();
 }
+
+fn run_to_eos(pipeline: &gst::Element) {
+    pipeline.set_state(gst::State::Playing).unwrap();
+
+    let bus = pipeline.bus().unwrap();
+    let msg = bus
+        .timed_pop_filtered(
+            gst::CLOCK_TIME_NONE,
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        )
+        .unwrap();
+    if let gst::MessageView::Error(err) = msg.view() {
+        panic!("pipeline error: {}", err.error());
+    }
+
+    pipeline.set_state(gst::State::Null).unwrap();
+}
+
+fn output_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn media_playlist(path: &std::path::Path) -> m3u8_rs::playlist::MediaPlaylist {
+    let content = std::fs::read(path).unwrap();
+    m3u8_rs::parse_media_playlist_res(&content).unwrap()
+}
+
+fn is_mpegts(data: &[u8]) -> bool {
+    !data.is_empty() && data.len() % 188 == 0 && data.chunks(188).all(|packet| packet[0] == 0x47)
+}
+
+#[test]
+fn test_fmp4_segments_reference_init_segment() {
+    init();
+
+    let dir = output_dir("flexhlssink-fmp4");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=120 ! x264enc ! h264parse ! \
+        flexhlssink target-duration=1 segment-format=fmp4 \
+        location={dir}/segment%05d.m4s init-location={dir}/init.mp4 \
+        playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.contains("#EXT-X-VERSION:6"));
+    assert!(playlist.contains("#EXT-X-MAP:URI="));
+    assert!(dir.join("init.mp4").exists());
+}
+
+#[test]
+fn test_low_latency_playlist_has_parts() {
+    init();
+
+    let dir = output_dir("flexhlssink-ll-hls");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=120 ! x264enc key-int-max=30 ! h264parse ! \
+        flexhlssink target-duration=1 part-duration=200 \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.contains("#EXT-X-PART-INF:PART-TARGET=0.200"));
+    assert!(playlist.contains("#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=0.600"));
+    assert!(playlist.contains("#EXT-X-PART:DURATION="));
+}
+
+#[test]
+fn test_master_playlist_lists_variants() {
+    init();
+
+    let dir = output_dir("flexhlssink-master");
+    let pipeline = gst::parse_launch(&format!(
+        "flexhlsmastersink name=hls target-duration=1 \
+        location={dir}/video%u_segment%05d.ts playlist-location={dir}/video%u.m3u8 \
+        master-playlist-location={dir}/master.m3u8 \
+        videotestsrc num-buffers=120 ! tee name=t \
+        t. ! queue ! video/x-raw,width=640,height=360 ! x264enc bitrate=800 ! h264parse ! hls.video_0 \
+        t. ! queue ! videoscale ! video/x-raw,width=320,height=180 ! x264enc bitrate=300 ! h264parse ! hls.video_1",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let master = std::fs::read_to_string(dir.join("master.m3u8")).unwrap();
+    assert_eq!(master.matches("#EXT-X-STREAM-INF:").count(), 2);
+    assert!(master.contains("RESOLUTION=640x360"));
+    assert!(master.contains("RESOLUTION=320x180"));
+    assert!(master.contains("video0.m3u8"));
+    assert!(master.contains("video1.m3u8"));
+    assert!(!dir.join("master.m3u8.tmp").exists());
+
+    // The bandwidth of a variant is the peak bitrate of its segments, as fetched by players.
+    let master = m3u8_rs::parse_master_playlist_res(master.as_bytes()).unwrap();
+    for variant in &master.variants {
+        let playlist = std::fs::read(dir.join(&variant.uri)).unwrap();
+        let playlist = m3u8_rs::parse_media_playlist_res(&playlist).unwrap();
+        let peak = playlist
+            .segments
+            .iter()
+            .map(|segment| {
+                let size = std::fs::metadata(dir.join(&segment.uri)).unwrap().len();
+                size as f64 * 8.0 / segment.duration as f64
+            })
+            .fold(0f64, f64::max);
+        let bandwidth = variant.bandwidth.parse::<f64>().unwrap();
+        assert!(
+            (bandwidth - peak).abs() < peak * 0.01,
+            "{} announced {} bps, its segments peak at {} bps",
+            variant.uri,
+            bandwidth,
+            peak
+        );
+    }
+}
+
+#[test]
+fn test_encrypted_segments_rotate_keys() {
+    init();
+
+    let dir = output_dir("flexhlssink-aes");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=150 ! x264enc key-int-max=30 ! h264parse ! \
+        flexhlssink name=hls target-duration=1 playlist-length=10 encryption=aes-128 key-rotation=2 \
+        location={dir}/segment%05d.ts key-location={dir}/key%05d.key \
+        playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    let flexhlssink = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("hls")
+        .unwrap();
+    flexhlssink
+        .connect("key-rotated", false, |args| {
+            let index = args[1].get::<u32>().unwrap();
+            Some(format!("https://keys.example.com/{}", index).to_value())
+        })
+        .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.contains("#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/0\""));
+    assert!(playlist.contains("URI=\"https://keys.example.com/1\""));
+    assert!(dir.join("key00000.key").exists());
+}
+
+#[test]
+fn test_rotated_keys_deleted_with_their_segments() {
+    init();
+
+    let dir = output_dir("flexhlssink-aes-delete");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=150 ! x264enc key-int-max=30 ! h264parse ! \
+        flexhlssink target-duration=1 playlist-length=2 max-files=2 encryption=aes-128 \
+        key-rotation=2 location={dir}/segment%05d.ts key-location={dir}/key%05d.key \
+        playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    // The segments of the first key are deleted, the third segment still uses the second key.
+    assert!(!dir.join("segment00001.ts").exists());
+    assert!(!dir.join("key00000.key").exists());
+    assert!(dir.join("segment00003.ts").exists());
+    assert!(dir.join("key00001.key").exists());
+}
+
+#[test]
+fn test_encryption_rejected_for_byte_ranges_and_init_segments() {
+    init();
+
+    for properties in &[
+        "segment-format=fmp4",
+        "single-file=true",
+        "iframe-playlist-location=iframes.m3u8",
+        "part-duration=200",
+    ] {
+        let pipeline = gst::parse_launch(&format!(
+            "videotestsrc ! x264enc ! h264parse ! flexhlssink encryption=aes-128 {}",
+            properties
+        ))
+        .unwrap();
+        assert!(pipeline.set_state(gst::State::Ready).is_err());
+        pipeline.set_state(gst::State::Null).unwrap();
+    }
+}
+
+#[test]
+fn test_key_handlers_can_read_stats() {
+    init();
+
+    let dir = output_dir("flexhlssink-aes-stats");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=150 ! x264enc key-int-max=30 ! h264parse ! \
+        flexhlssink name=hls target-duration=1 playlist-length=10 encryption=aes-128 key-rotation=2 \
+        location={dir}/segment%05d.ts key-location={dir}/key%05d.key \
+        playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    let flexhlssink = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("hls")
+        .unwrap();
+    flexhlssink
+        .connect("key-rotated", false, |args| {
+            let element = args[0].get::<gst::Element>().unwrap();
+            let stats = element
+                .property("stats")
+                .unwrap()
+                .get::<gst::Structure>()
+                .unwrap();
+            assert!(stats.get::<u64>("segments-written").is_ok());
+            Some(format!("key{}.key", args[1].get::<u32>().unwrap()).to_value())
+        })
+        .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = media_playlist(&dir.join("playlist.m3u8"));
+    assert!(playlist.segments.len() > 2);
+    for (index, segment) in playlist.segments.iter().enumerate() {
+        // Each key encrypts two segments, its IV is the sequence number of the segment.
+        let key_index = index / 2;
+        let key = segment.key.as_ref().unwrap();
+        assert_eq!(
+            key.uri.as_deref(),
+            Some(format!("key{}.key", key_index).as_str())
+        );
+        let iv = playlist.media_sequence as u128 + index as u128;
+        assert_eq!(key.iv.as_deref(), Some(format!("0x{:032X}", iv).as_str()));
+
+        let key = std::fs::read(dir.join(format!("key{:05}.key", key_index))).unwrap();
+        let encrypted = std::fs::read(dir.join(&segment.uri)).unwrap();
+        let decrypted = Aes128Cbc::new_from_slices(&key, &iv.to_be_bytes())
+            .unwrap()
+            .decrypt_vec(&encrypted)
+            .unwrap();
+        assert!(is_mpegts(&decrypted), "{} does not decrypt", segment.uri);
+    }
+}
+
+#[test]
+fn test_playlist_root_prefixes_segment_uris() {
+    init();
+
+    let dir = output_dir("flexhlssink-playlist-root");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=60 ! x264enc ! h264parse ! \
+        flexhlssink target-duration=1 playlist-root=https://cdn.example/cam1/ \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.contains("\nhttps://cdn.example/cam1/segment00000.ts\n"));
+    assert!(!playlist.contains(&dir.display().to_string()));
+}
+
+#[test]
+fn test_playlist_replaced_atomically() {
+    init();
+
+    let dir = output_dir("flexhlssink-atomic-playlist");
+    // A stale, longer playlist must not leave garbage at the end of the new one.
+    std::fs::write(dir.join("playlist.m3u8"), "x".repeat(64 * 1024)).unwrap();
+
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=60 ! x264enc ! h264parse ! \
+        flexhlssink target-duration=1 \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.starts_with("#EXTM3U"));
+    assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+    assert!(!dir.join("playlist.m3u8.tmp").exists());
+}
+
+#[test]
+fn test_discontinuity_starts_new_segment() {
+    init();
+
+    let dir = output_dir("flexhlssink-discontinuity");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=90 ! x264enc key-int-max=15 ! h264parse name=parse ! \
+        flexhlssink target-duration=1 \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    // Flag a buffer in the middle of the stream as discontinuous.
+    let parse = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("parse")
+        .unwrap();
+    let buffer_count = std::sync::atomic::AtomicUsize::new(0);
+    parse
+        .static_pad("src")
+        .unwrap()
+        .add_probe(gst::PadProbeType::BUFFER, move |_pad, info| {
+            if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+                if buffer_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 40 {
+                    buffer.make_mut().set_flags(gst::BufferFlags::DISCONT);
+                }
+            }
+            gst::PadProbeReturn::Ok
+        })
+        .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert_eq!(playlist.matches("#EXT-X-DISCONTINUITY\n").count(), 1);
+}
+
+#[test]
+fn test_gap_followed_by_continuous_buffers_is_not_a_discontinuity() {
+    init();
+
+    let dir = output_dir("flexhlssink-gap");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=90 ! x264enc key-int-max=15 ! h264parse name=parse ! \
+        flexhlssink target-duration=1 \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    // Announce a gap in the middle of the stream, the next buffer following it.
+    let parse = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("parse")
+        .unwrap();
+    let buffer_count = std::sync::atomic::AtomicUsize::new(0);
+    parse
+        .static_pad("src")
+        .unwrap()
+        .add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                if buffer_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 40 {
+                    pad.push_event(gst::event::Gap::new(
+                        buffer.pts(),
+                        gst::ClockTime::from_nseconds(0),
+                    ));
+                }
+            }
+            gst::PadProbeReturn::Ok
+        })
+        .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(!playlist.contains("#EXT-X-DISCONTINUITY"));
+}
+
+#[test]
+fn test_program_date_time_on_each_segment() {
+    init();
+
+    let dir = output_dir("flexhlssink-program-date-time");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=90 ! x264enc ! h264parse ! \
+        flexhlssink target-duration=1 program-date-time=clock \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read(dir.join("playlist.m3u8")).unwrap();
+    let playlist = m3u8_rs::parse_media_playlist_res(&playlist).unwrap();
+    let date_times = playlist
+        .segments
+        .iter()
+        .map(|segment| {
+            chrono::DateTime::parse_from_rfc3339(segment.program_date_time.as_ref().unwrap())
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    // Segments follow each other on the wall clock as they do in running time.
+    for (segment, date_times) in playlist.segments.iter().zip(date_times.windows(2)) {
+        let elapsed = (date_times[1] - date_times[0]).num_milliseconds();
+        assert!((elapsed - (segment.duration * 1_000.0).round() as i64).abs() <= 2);
+    }
+}
+
+#[test]
+fn test_vod_playlist_keeps_all_segments() {
+    init();
+
+    let dir = output_dir("flexhlssink-vod");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=150 ! x264enc ! h264parse ! \
+        flexhlssink target-duration=1 playlist-type=vod playlist-length=2 max-files=2 \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.contains("#EXT-X-PLAYLIST-TYPE:VOD"));
+    assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:0"));
+    assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+    assert!(playlist.matches("#EXTINF:").count() > 2);
+    assert!(dir.join("segment00000.ts").exists());
+}
+
+#[test]
+fn test_single_file_byte_range_segments() {
+    init();
+
+    let dir = output_dir("flexhlssink-single-file");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=90 ! x264enc ! h264parse ! \
+        flexhlssink target-duration=1 single-file=true \
+        location={dir}/media%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.contains("#EXT-X-BYTERANGE:"));
+    assert!(!dir.join("media00001.ts").exists());
+
+    // The byte ranges cover the whole file, one segment after the other.
+    let mut expected_offset = 0;
+    for line in playlist.lines() {
+        if let Some(range) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            let mut range = range.split('@');
+            let length = range.next().unwrap().parse::<u64>().unwrap();
+            let offset = range.next().unwrap().parse::<u64>().unwrap();
+            assert_eq!(offset, expected_offset);
+            expected_offset += length;
+        }
+    }
+    let file_size = std::fs::metadata(dir.join("media00000.ts")).unwrap().len();
+    assert_eq!(expected_offset, file_size);
+}
+
+#[test]
+fn test_iframe_playlist_lists_keyframes() {
+    init();
+
+    let dir = output_dir("flexhlssink-iframes");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=90 ! x264enc key-int-max=15 ! h264parse ! \
+        flexhlssink target-duration=2 iframe-playlist-location={dir}/iframes.m3u8 \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    let iframes = std::fs::read_to_string(dir.join("iframes.m3u8")).unwrap();
+    assert!(iframes.contains("#EXT-X-I-FRAMES-ONLY"));
+    assert!(iframes.ends_with("#EXT-X-ENDLIST\n"));
+    // Several keyframes per segment, each addressed by its byte range.
+    assert!(iframes.matches("#EXT-X-BYTERANGE:").count() > playlist.matches("#EXTINF:").count());
+    assert!(iframes.contains("\nsegment00000.ts\n"));
+}
+
+#[test]
+fn test_subtitle_playlist_follows_video_segments() {
+    init();
+
+    let dir = output_dir("flexhlssink-subtitles");
+    std::fs::write(
+        dir.join("captions.srt"),
+        "1\n00:00:00,500 --> 00:00:01,500\nFirst caption\n\n\
+        2\n00:00:02,000 --> 00:00:02,800\nSecond caption\n",
+    )
+    .unwrap();
+
+    let pipeline = gst::parse_launch(&format!(
+        "flexhlssink name=hls target-duration=1 \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8 \
+        subtitle-location={dir}/subtitle%u_segment%05d.vtt \
+        subtitle-playlist-location={dir}/subtitle%u.m3u8 \
+        videotestsrc num-buffers=90 ! x264enc ! h264parse ! hls.video \
+        filesrc location={dir}/captions.srt ! subparse ! hls.subtitle_0",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    let subtitles = std::fs::read_to_string(dir.join("subtitle0.m3u8")).unwrap();
+    assert_eq!(
+        subtitles.matches("#EXTINF:").count(),
+        playlist.matches("#EXTINF:").count()
+    );
+    assert!(subtitles.contains("subtitle0_segment00000.vtt"));
+
+    let segment = std::fs::read_to_string(dir.join("subtitle0_segment00000.vtt")).unwrap();
+    assert!(segment.starts_with("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:324000000,LOCAL:00:00:00.000\n"));
+    assert!(segment.contains("00:00:00.500 --> 00:00:01.500\nFirst caption\n"));
+}
+
+#[test]
+fn test_master_playlist_lists_audio_renditions() {
+    init();
+
+    let dir = output_dir("flexhlssink-master-audio");
+    let pipeline = gst::parse_launch(&format!(
+        "flexhlsmastersink name=hls target-duration=1 \
+        location={dir}/video%u_segment%05d.ts playlist-location={dir}/video%u.m3u8 \
+        audio-location={dir}/audio%u_segment%05d.ts audio-playlist-location={dir}/audio%u.m3u8 \
+        master-playlist-location={dir}/master.m3u8 \
+        videotestsrc num-buffers=120 ! x264enc ! h264parse ! hls.video_0 \
+        audiotestsrc num-buffers=172 ! avenc_aac ! \
+        taginject tags=\"language-code=(string)en,title=(string)English\" ! hls.audio_0 \
+        audiotestsrc num-buffers=172 ! avenc_aac ! \
+        taginject tags=\"language-code=(string)pt,title=(string)Português\" ! hls.audio_1",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let master = std::fs::read_to_string(dir.join("master.m3u8")).unwrap();
+    assert_eq!(master.matches("#EXT-X-MEDIA:TYPE=AUDIO").count(), 2);
+    assert_eq!(master.matches("DEFAULT=YES").count(), 1);
+    assert!(master.contains("LANGUAGE=\"en\""));
+    assert!(master.contains("NAME=\"Português\""));
+    assert!(master.contains("AUDIO=\"audio\""));
+    assert!(master.contains("mp4a.40.2"));
+    assert!(dir.join("audio1.m3u8").exists());
+}
+
+#[test]
+fn test_packed_audio_segments_start_with_timestamp() {
+    init();
+
+    let dir = output_dir("flexhlssink-packed-audio");
+    let pipeline = gst::parse_launch(&format!(
+        "flexhlssink name=hls target-duration=1 segment-format=packed-audio \
+        location={dir}/segment%05d.aac playlist-location={dir}/playlist.m3u8 \
+        audiotestsrc num-buffers=172 ! avenc_aac ! aacparse ! \
+        audio/mpeg,stream-format=adts ! hls.audio_0",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.contains("segment00000.aac"));
+
+    let segment = std::fs::read(dir.join("segment00000.aac")).unwrap();
+    assert_eq!(&segment[..4], b"ID3\x04");
+    assert_eq!(
+        &segment[20..64],
+        b"com.apple.streaming.transportStreamTimestamp"
+    );
+    // The ADTS stream follows the 73 bytes ID3 tag.
+    assert_eq!(segment[73], 0xff);
+    assert_eq!(segment[74] & 0xf0, 0xf0);
+}
+
+#[test]
+fn test_audio_pads_requested_next_to_meta_pad() {
+    init();
+
+    let flexhlssink = gst::ElementFactory::make("flexhlssink", None).unwrap();
+    flexhlssink.request_pad_simple("meta").unwrap();
+
+    // The meta pad does not take the index of an audio pad.
+    let audio_pad = flexhlssink.request_pad_simple("audio_0").unwrap();
+    assert_eq!(audio_pad.name(), "audio_0");
+
+    // Alternate audio renditions are written by flexhlsmastersink, a single audio stream is
+    // muxed into the segments, whatever the name of its pad.
+    assert!(flexhlssink.request_pad_simple("audio_1").is_none());
+    assert!(flexhlssink.request_pad_simple("audio").is_none());
+
+    let flexhlssink = gst::ElementFactory::make("flexhlssink", None).unwrap();
+    let audio_pad = flexhlssink.request_pad_simple("audio").unwrap();
+    assert_eq!(audio_pad.name(), "audio");
+    assert!(flexhlssink.request_pad_simple("audio_0").is_none());
+}
+
+#[test]
+fn test_inserted_metadata_muxed_as_id3() {
+    init();
+
+    let dir = output_dir("flexhlssink-meta");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=120 ! x264enc tune=zerolatency ! h264parse ! \
+        identity name=id signal-handoffs=true ! \
+        flexhlssink name=hls target-duration=1 \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    let bin = pipeline.downcast_ref::<gst::Bin>().unwrap();
+    let flexhlssink = bin.by_name("hls").unwrap();
+    flexhlssink.request_pad_simple("meta").unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    let sender = std::sync::Mutex::new(sender);
+    let buffer_count = std::sync::atomic::AtomicU32::new(0);
+    bin.by_name("id")
+        .unwrap()
+        .connect("handoff", false, move |_args| {
+            if buffer_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 60 {
+                let mut tags = gst::TagList::new();
+                tags.get_mut()
+                    .unwrap()
+                    .add::<gst::tags::Title>(&"Song title", gst::TagMergeMode::Replace);
+                let inserted = flexhlssink
+                    .emit_by_name("insert-metadata", &[&tags])
+                    .unwrap()
+                    .unwrap()
+                    .get::<bool>()
+                    .unwrap();
+                sender.lock().unwrap().send(inserted).unwrap();
+            }
+            None
+        })
+        .unwrap();
+
+    run_to_eos(&pipeline);
+
+    assert!(receiver.recv().unwrap());
+    let found = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "ts"))
+        .any(|path| {
+            let segment = std::fs::read(path).unwrap();
+            segment
+                .windows(b"Song title".len())
+                .any(|window| window == b"Song title")
+        });
+    assert!(found);
+}
+
+#[test]
+fn test_splice_event_marks_ad_break() {
+    init();
+
+    let dir = output_dir("flexhlssink-scte35");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=150 ! x264enc key-int-max=15 ! h264parse ! \
+        identity name=id signal-handoffs=true ! \
+        flexhlssink target-duration=10 cue-tags=true \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    let identity = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("id")
+        .unwrap();
+    let src_pad = identity.static_pad("src").unwrap();
+    let buffer_count = std::sync::atomic::AtomicU32::new(0);
+    identity
+        .connect("handoff", false, move |_args| {
+            let splice = match buffer_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                45 => gst::Structure::builder("scte35-splice")
+                    .field("event-id", &7u32)
+                    .field("out-of-network", &true)
+                    .field("duration", &gst::ClockTime::from_seconds(30))
+                    .field(
+                        "splice-info",
+                        &glib::Bytes::from_static(&[0xfc, 0x30, 0x11]),
+                    )
+                    .build(),
+                105 => gst::Structure::builder("scte35-splice")
+                    .field("event-id", &7u32)
+                    .field("out-of-network", &false)
+                    .build(),
+                _ => return None,
+            };
+            src_pad.push_event(gst::event::CustomDownstream::new(splice));
+            None
+        })
+        .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert_eq!(playlist.matches("#EXTINF:").count(), 3);
+    assert!(playlist.contains("#EXT-X-CUE-OUT:DURATION=30.000"));
+    assert!(playlist.contains("#EXT-X-CUE-IN"));
+
+    let playlist = m3u8_rs::parse_media_playlist_res(playlist.as_bytes()).unwrap();
+    let out_daterange = playlist.segments[1].daterange.as_ref().unwrap();
+    assert!(out_daterange.contains("PLANNED-DURATION=30.000,SCTE35-OUT=0xFC3011"));
+
+    // The end of the ad break closes the date range of its start.
+    let attribute = |daterange: &str, name: &str| {
+        daterange
+            .split(',')
+            .find_map(|attribute| attribute.strip_prefix(&format!("{}=", name)))
+            .map(|value| value.trim_matches('"').to_string())
+    };
+    let in_daterange = playlist.segments[2].daterange.as_ref().unwrap();
+    assert_eq!(attribute(out_daterange, "ID"), Some("splice-7".to_string()));
+    assert_eq!(
+        attribute(in_daterange, "ID"),
+        attribute(out_daterange, "ID")
+    );
+    assert_eq!(
+        attribute(in_daterange, "START-DATE"),
+        attribute(out_daterange, "START-DATE")
+    );
+    assert_eq!(
+        attribute(in_daterange, "END-DATE"),
+        playlist.segments[2].program_date_time.clone()
+    );
+}
+
+#[test]
+fn test_split_now_ends_segment() {
+    init();
+
+    let dir = output_dir("flexhlssink-split-now");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=150 ! x264enc key-int-max=15 ! h264parse ! \
+        identity name=id signal-handoffs=true ! \
+        flexhlssink name=hls target-duration=10 \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    let bin = pipeline.downcast_ref::<gst::Bin>().unwrap();
+    let flexhlssink = bin.by_name("hls").unwrap();
+    let identity = bin.by_name("id").unwrap();
+    let src_pad = identity.static_pad("src").unwrap();
+    let buffer_count = std::sync::atomic::AtomicU32::new(0);
+    identity
+        .connect("handoff", false, move |_args| {
+            match buffer_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                30 => {
+                    flexhlssink.emit_by_name("split-now", &[]).unwrap();
+                }
+                90 => {
+                    let event =
+                        gst::event::CustomDownstream::new(gst::Structure::new_empty("split-now"));
+                    src_pad.push_event(event);
+                }
+                _ => {}
+            }
+            None
+        })
+        .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert_eq!(playlist.matches("#EXTINF:").count(), 3);
+}
+
+#[test]
+fn test_epoch_aligned_segments_numbered_after_epoch() {
+    init();
+
+    let dir = output_dir("flexhlssink-epoch");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc is-live=true num-buffers=120 ! x264enc tune=zerolatency key-int-max=15 ! \
+        h264parse ! flexhlssink target-duration=1 align-to-epoch=true \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    let media_sequence = playlist
+        .lines()
+        .find_map(|line| line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:"))
+        .unwrap()
+        .parse::<u64>()
+        .unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    assert!(media_sequence > now - 60 && media_sequence <= now);
+    assert!(playlist.contains(&format!("segment{}.ts", media_sequence)));
+}
+
+#[test]
+fn test_epoch_alignment_rejected_without_target_duration() {
+    init();
+
+    let pipeline = gst::parse_launch(
+        "videotestsrc ! x264enc ! h264parse ! flexhlssink target-duration=0 align-to-epoch=true",
+    )
+    .unwrap();
+    assert!(pipeline.set_state(gst::State::Ready).is_err());
+    pipeline.set_state(gst::State::Null).unwrap();
+}
+
+#[test]
+fn test_resume_continues_existing_playlist() {
+    init();
+
+    let dir = output_dir("flexhlssink-resume");
+    let launch = format!(
+        "videotestsrc num-buffers=90 ! x264enc ! h264parse ! \
+        flexhlssink target-duration=1 playlist-length=0 resume=true \
+        location={dir}/segment%05d.ts playlist-location={dir}/playlist.m3u8",
+        dir = dir.display()
+    );
+
+    run_to_eos(&gst::parse_launch(&launch).unwrap());
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    let first_run_segments = playlist.matches("#EXTINF:").count();
+    assert!(!playlist.contains("#EXT-X-DISCONTINUITY"));
+
+    run_to_eos(&gst::parse_launch(&launch).unwrap());
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.matches("#EXTINF:").count() > first_run_segments);
+    assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:0"));
+    assert!(playlist.contains("segment00000.ts"));
+    // The first segment of the second run follows a discontinuity.
+    let first_resumed_segment = format!("segment{:05}.ts", first_run_segments);
+    let discontinuity = playlist.find("#EXT-X-DISCONTINUITY\n").unwrap();
+    assert!(discontinuity < playlist.find(&first_resumed_segment).unwrap());
+}
+
+#[test]
+fn test_resume_fails_on_unreadable_playlist() {
+    init();
+
+    // The playlist location is a directory, which can not be read as a playlist.
+    let dir = output_dir("flexhlssink-resume-unreadable");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc ! x264enc ! h264parse ! \
+        flexhlssink resume=true location={dir}/segment%05d.ts playlist-location={dir}",
+        dir = dir.display()
+    ))
+    .unwrap();
+    assert!(pipeline.set_state(gst::State::Ready).is_err());
+    pipeline.set_state(gst::State::Null).unwrap();
+}
+
+#[test]
+fn test_memory_storage_holds_segments_and_playlist() {
+    init();
+
+    let pipeline = gst::parse_launch(
+        "videotestsrc num-buffers=90 ! x264enc ! h264parse ! \
+        flexhlssink name=hls target-duration=1 \
+        location=memory/segment%05d.ts playlist-location=memory/playlist.m3u8",
+    )
+    .unwrap();
+
+    let storage = flexhlssink::MemoryStorage::new();
+    pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("hls")
+        .unwrap()
+        .downcast::<flexhlssink::FlexHlsSink>()
+        .unwrap()
+        .set_storage(storage.clone());
+
+    run_to_eos(&pipeline);
+
+    let playlist = String::from_utf8(storage.get("memory/playlist.m3u8").unwrap()).unwrap();
+    assert!(playlist.contains("segment00000.ts"));
+    assert!(!storage.get("memory/segment00000.ts").unwrap().is_empty());
+    assert!(storage.get("memory/playlist.m3u8.tmp").is_none());
+    assert!(!std::path::Path::new("memory").exists());
+}
+
+/// Starts an HTTP server recording the requests it gets as `(method, path, body length)`,
+/// rate limiting the first one.
+fn start_upload_server() -> (
+    String,
+    std::sync::Arc<std::sync::Mutex<Vec<(String, String, usize)>>>,
+) {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}/live", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(vec![]));
+    let rate_limited = Arc::new(AtomicBool::new(false));
+
+    let server_requests = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let requests = server_requests.clone();
+            let rate_limited = rate_limited.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut request_line = String::new();
+                    if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                        break;
+                    }
+                    let mut content_length = 0;
+                    loop {
+                        let mut header = String::new();
+                        reader.read_line(&mut header).unwrap();
+                        let header = header.trim_end();
+                        if header.is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = header.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).unwrap();
+
+                    let mut parts = request_line.split_whitespace();
+                    let method = parts.next().unwrap().to_string();
+                    let path = parts.next().unwrap().to_string();
+                    let response = if !rate_limited.swap(true, Ordering::SeqCst) {
+                        "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Length: 0\r\n\r\n"
+                    } else {
+                        requests.lock().unwrap().push((method, path, body.len()));
+                        "HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n"
+                    };
+                    stream.write_all(response.as_bytes()).unwrap();
+                }
+            });
+        }
+    });
+
+    (base_url, requests)
+}
+
+#[test]
+fn test_upload_puts_segments_and_playlists() {
+    init();
+
+    let (base_url, requests) = start_upload_server();
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=90 ! x264enc ! h264parse ! \
+        flexhlssink target-duration=1 upload-url={} upload-retries=3 \
+        location=segment%05d.ts playlist-location=playlist.m3u8",
+        base_url
+    ))
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let requests = requests.lock().unwrap();
+    let puts = |path: &str| {
+        requests
+            .iter()
+            .filter(|(method, request_path, length)| {
+                method == "PUT" && request_path == path && *length > 0
+            })
+            .count()
+    };
+    // The first upload was rate limited, then retried.
+    assert_eq!(puts("/live/segment00000.ts"), 1);
+    assert_eq!(puts("/live/segment00001.ts"), 1);
+    assert!(puts("/live/playlist.m3u8") >= 2);
+    assert!(!std::path::Path::new("segment00000.ts").exists());
+    assert!(!std::path::Path::new("playlist.m3u8").exists());
+}
+
+#[test]
+fn test_write_queue_writes_from_writer_thread() {
+    init();
+
+    let dir = output_dir("flexhlssink_write_queue");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=90 ! x264enc ! h264parse ! \
+        flexhlssink name=hls target-duration=1 write-queue-size=4 \
+        location={0}/segment%05d.ts playlist-location={0}/playlist.m3u8",
+        dir.display()
+    ))
+    .unwrap();
+
+    let hls = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("hls")
+        .unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let sender = std::sync::Mutex::new(sender);
+    hls.connect("commit-playlist-stream", false, move |_args| {
+        let thread_name = thread::current().name().map(String::from);
+        let _ = sender.lock().unwrap().send(thread_name);
+        Some(true.to_value())
+    })
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let thread_names = receiver.try_iter().collect::<Vec<_>>();
+    assert!(!thread_names.is_empty());
+    assert!(thread_names
+        .iter()
+        .all(|name| name.as_deref() == Some("flexhlssink-writer")));
+
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.contains("segment00000.ts"));
+    assert!(playlist.contains("#EXT-X-ENDLIST"));
+    assert!(!playlist.contains("#EXT-X-GAP"));
+    assert!(dir.join("segment00000.ts").exists());
+}
+
+#[test]
+fn test_full_write_queue_posts_error() {
+    init();
+
+    let dir = output_dir("flexhlssink_write_queue_full");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=300 ! x264enc ! h264parse ! \
+        flexhlssink name=hls target-duration=1 write-queue-size=1 write-queue-full=error \
+        location={0}/segment%05d.ts playlist-location={0}/playlist.m3u8",
+        dir.display()
+    ))
+    .unwrap();
+
+    // Stalls the writer thread on the first playlist, so the queue fills up.
+    let hls = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("hls")
+        .unwrap();
+    hls.connect("commit-playlist-stream", false, |_args| {
+        thread::sleep(Duration::from_secs(2));
+        Some(true.to_value())
+    })
+    .unwrap();
+
+    pipeline.set_state(gst::State::Playing).unwrap();
+    let msg = pipeline
+        .bus()
+        .unwrap()
+        .timed_pop_filtered(
+            gst::CLOCK_TIME_NONE,
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        )
+        .unwrap();
+    assert!(matches!(msg.view(), gst::MessageView::Error(_)));
+    pipeline.set_state(gst::State::Null).unwrap();
+}
+
+#[test]
+fn test_segment_and_playlist_messages_posted() {
+    init();
+
+    let dir = output_dir("flexhlssink_messages");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=150 ! x264enc key-int-max=30 ! h264parse ! \
+        flexhlssink target-duration=1 max-files=2 playlist-length=2 \
+        location={0}/segment%05d.ts playlist-location={0}/playlist.m3u8",
+        dir.display()
+    ))
+    .unwrap();
+    pipeline.set_state(gst::State::Playing).unwrap();
+
+    let mut added = vec![];
+    let mut removed = vec![];
+    let mut last_playlist = None;
+    let bus = pipeline.bus().unwrap();
+    for msg in bus.iter_timed(gst::CLOCK_TIME_NONE) {
+        match msg.view() {
+            gst::MessageView::Element(element) => {
+                let s = element.structure().unwrap();
+                match s.name() {
+                    "hls-segment-added" => added.push(s.to_owned()),
+                    "hls-segment-removed" => removed.push(s.to_owned()),
+                    "hls-playlist-updated" => {
+                        last_playlist = Some(s.get::<String>("playlist").unwrap())
+                    }
+                    _ => (),
+                }
+            }
+            gst::MessageView::Eos(..) => break,
+            gst::MessageView::Error(err) => panic!("pipeline error: {}", err.error()),
+            _ => (),
+        }
+    }
+    pipeline.set_state(gst::State::Null).unwrap();
+
+    assert!(added.len() >= 4);
+    let first = &added[0];
+    assert_eq!(first.get::<String>("uri").unwrap(), "segment00000.ts");
+    assert_eq!(first.get::<u64>("sequence").unwrap(), 0);
+    assert!(first.get::<u64>("size").unwrap() > 0);
+    assert!(first.get::<gst::ClockTime>("duration").unwrap() > gst::ClockTime::from_nseconds(0));
+    assert!(first.get::<bool>("keyframe").unwrap());
+
+    assert_eq!(removed.len(), added.len() - 2);
+    assert_eq!(
+        removed[0].get::<String>("location").unwrap(),
+        dir.join("segment00000.ts").display().to_string()
+    );
+
+    let last_playlist = last_playlist.unwrap();
+    assert_eq!(
+        last_playlist,
+        std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap()
+    );
+    assert!(last_playlist.contains("#EXT-X-ENDLIST"));
+}
+
+#[test]
+fn test_stats_count_segments_and_playlists() {
+    init();
+
+    let dir = output_dir("flexhlssink_stats");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=150 ! x264enc key-int-max=30 ! h264parse ! \
+        flexhlssink name=hls target-duration=1 max-files=2 \
+        location={0}/segment%05d.ts playlist-location={0}/playlist.m3u8",
+        dir.display()
+    ))
+    .unwrap();
+    run_to_eos(&pipeline);
+
+    let hls = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("hls")
+        .unwrap();
+    let stats = hls
+        .property("stats")
+        .unwrap()
+        .get::<gst::Structure>()
+        .unwrap();
+
+    let segments_written = stats.get::<u64>("segments-written").unwrap();
+    assert!(segments_written >= 4);
+    assert_eq!(
+        stats.get::<u64>("segments-deleted").unwrap(),
+        segments_written - 2
+    );
+    assert!(stats.get::<u64>("bytes-written").unwrap() > 0);
+    assert!(stats.get::<u64>("peak-bitrate").unwrap() >= stats.get::<u64>("bitrate").unwrap());
+    assert!(
+        stats.get::<gst::ClockTime>("min-segment-duration").unwrap()
+            <= stats.get::<gst::ClockTime>("max-segment-duration").unwrap()
+    );
+    assert!(stats.get::<u64>("playlists-written").unwrap() > segments_written);
+    assert_eq!(stats.get::<u64>("playlist-write-failures").unwrap(), 0);
+    assert_eq!(stats.get::<u64>("target-duration-violations").unwrap(), 0);
+}
+
+#[test]
+fn test_playlist_write_failures_not_fatal() {
+    init();
+
+    let dir = output_dir("flexhlssink_playlist_write_failures");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=90 ! x264enc key-int-max=30 ! h264parse ! \
+        flexhlssink name=hls target-duration=1 write-queue-size=0 \
+        location={0}/segment%05d.ts playlist-location={0}/missing/playlist.m3u8",
+        dir.display()
+    ))
+    .unwrap();
+
+    pipeline.set_state(gst::State::Playing).unwrap();
+    let bus = pipeline.bus().unwrap();
+    let mut warnings = 0;
+    loop {
+        let msg = bus
+            .timed_pop_filtered(
+                gst::CLOCK_TIME_NONE,
+                &[
+                    gst::MessageType::Eos,
+                    gst::MessageType::Error,
+                    gst::MessageType::Warning,
+                ],
+            )
+            .unwrap();
+        match msg.view() {
+            gst::MessageView::Warning(_) => warnings += 1,
+            gst::MessageView::Error(err) => panic!("pipeline error: {}", err.error()),
+            _ => break,
+        }
+    }
+    pipeline.set_state(gst::State::Null).unwrap();
+
+    let stats = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("hls")
+        .unwrap()
+        .property("stats")
+        .unwrap()
+        .get::<gst::Structure>()
+        .unwrap();
+    let failures = stats.get::<u64>("playlist-write-failures").unwrap();
+    assert!(failures >= 2);
+    assert!(warnings >= failures);
+    assert_eq!(stats.get::<u64>("playlists-written").unwrap(), 0);
+    assert!(dir.join("segment00001.ts").exists());
+}
+
+#[test]
+fn test_failed_playlist_commits_counted_once() {
+    init();
+
+    let dir = output_dir("flexhlssink_playlist_commit_failures");
+    let pipeline = gst::parse_launch(&format!(
+        "videotestsrc num-buffers=90 ! x264enc key-int-max=30 ! h264parse ! \
+        flexhlssink name=hls target-duration=1 write-queue-size=0 \
+        location={0}/segment%05d.ts playlist-location={0}/playlist.m3u8",
+        dir.display()
+    ))
+    .unwrap();
+
+    let hls = pipeline
+        .downcast_ref::<gst::Bin>()
+        .unwrap()
+        .by_name("hls")
+        .unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let sender = std::sync::Mutex::new(sender);
+    hls.connect("commit-playlist-stream", false, move |_args| {
+        let _ = sender.lock().unwrap().send(());
+        Some(false.to_value())
+    })
+    .unwrap();
+
+    run_to_eos(&pipeline);
+
+    let stats = hls
+        .property("stats")
+        .unwrap()
+        .get::<gst::Structure>()
+        .unwrap();
+    let commits = receiver.try_iter().count() as u64;
+    assert!(commits >= 2);
+    assert_eq!(
+        stats.get::<u64>("playlist-write-failures").unwrap(),
+        commits
+    );
+    assert_eq!(stats.get::<u64>("playlists-written").unwrap(), 0);
+    // The temporary file is never renamed.
+    assert!(!dir.join("playlist.m3u8").exists());
+}
+
+#[test]
+fn test_late_keyframes_within_target_duration() {
+    init();
+
+    let launch = |name: &str, policy: &str| {
+        let dir = output_dir(name);
+        let pipeline = gst::parse_launch(&format!(
+            "videotestsrc num-buffers=180 ! x264enc key-int-max=60 ! h264parse ! \
+            flexhlssink name=hls target-duration=1 send-keyframe-requests=false \
+            target-duration-policy={1} playlist-type=event \
+            location={0}/segment%05d.ts playlist-location={0}/playlist.m3u8",
+            dir.display(),
+            policy
+        ))
+        .unwrap();
+        pipeline.set_state(gst::State::Playing).unwrap();
+
+        let mut warnings = 0;
+        let mut error = None;
+        let bus = pipeline.bus().unwrap();
+        for msg in bus.iter_timed(gst::CLOCK_TIME_NONE) {
+            match msg.view() {
+                gst::MessageView::Warning(..) => warnings += 1,
+                gst::MessageView::Error(err) => {
+                    error = Some(err.error());
+                    break;
+                }
+                gst::MessageView::Eos(..) => break,
+                _ => (),
+            }
+        }
+        let violations = pipeline
+            .downcast_ref::<gst::Bin>()
+            .unwrap()
+            .by_name("hls")
+            .unwrap()
+            .property("stats")
+            .unwrap()
+            .get::<gst::Structure>()
+            .unwrap()
+            .get::<u64>("target-duration-violations")
+            .unwrap();
+        pipeline.set_state(gst::State::Null).unwrap();
+        (dir, warnings, violations, error)
+    };
+
+    // Segments of two seconds do not fit in a target duration of one second, they are left
+    // out of the playlist without stopping the pipeline.
+    let (dir, warnings, violations, error) = launch("flexhlssink_target_duration_strict", "strict");
+    assert!(error.is_none());
+    assert!(warnings > 0);
+    assert!(violations > 0);
+    assert!(!dir.join("segment00000.ts").exists());
+    if let Ok(playlist) = std::fs::read_to_string(dir.join("playlist.m3u8")) {
+        assert!(!playlist.contains("#EXTINF"));
+    }
+
+    let (dir, warnings, violations, error) = launch("flexhlssink_target_duration_bump", "bump");
+    assert!(error.is_none());
+    assert!(warnings > 0);
+    assert!(violations > 0);
+    let playlist = std::fs::read_to_string(dir.join("playlist.m3u8")).unwrap();
+    assert!(playlist.contains("#EXT-X-TARGETDURATION:2"));
+
+    // Without a target duration, segments only end at keyframes.
+    let dir = output_dir("flexhlssink_target_duration_none");
+    run_to_eos(
+        &gst::parse_launch(&format!(
+            "videotestsrc num-buffers=180 ! x264enc key-int-max=60 ! h264parse ! \
+            flexhlssink target-duration=0 target-duration-policy=strict \
+            location={0}/segment%05d.ts playlist-location={0}/playlist.m3u8",
+            dir.display()
+        ))
+        .unwrap(),
+    );
+    assert!(dir.join("segment00000.ts").exists());
+}